@@ -1,5 +1,5 @@
 use crate::ray::Ray;
-use crate::vec3::Vec3;
+use crate::vec3::{gamma, Float, Vec3};
 
 /// Struct describes a minimal axis aligned bounding box, use withink the ray tracing algorithm to
 /// detect ray intersection
@@ -22,7 +22,7 @@ impl Aabb {
         self.max
     }
 
-    pub fn hit(&self, ray: &Ray, mut tmin: f64, mut tmax: f64) -> bool {
+    pub fn hit(&self, ray: &Ray, mut tmin: Float, mut tmax: Float) -> bool {
         for i in 0..3 {
             let t0 = ((self.min[i] - ray.origin()[i]) / ray.direction()[i])
                 .min((self.max[i] - ray.origin()[i]) / ray.direction()[i]);
@@ -30,6 +30,10 @@ impl Aabb {
             let t1 = ((self.min[i] - ray.origin()[i]) / ray.direction()[i])
                 .max((self.max[i] - ray.origin()[i]) / ray.direction()[i]);
 
+            // NOTE: Widen the far slab by the rounding error of computing it so that rays grazing
+            // the box are never culled, see PBR 3.9.2
+            let t1 = t1 * (1.0 + 2.0 * gamma(3));
+
             tmin = t0.max(tmin);
             tmax = t1.min(tmax);
 
@@ -1,5 +1,5 @@
-use crate::ray::Ray;
-use crate::vec3::Vec3;
+use crate::ray::{Ray, RayDifferential};
+use crate::vec3::{Float, Vec3, PI};
 use rand::Rng;
 
 /// Struct describes the rendering camera.
@@ -9,27 +9,30 @@ pub struct Camera {
     llc: Vec3,
     horizontal: Vec3,
     vertical: Vec3,
-    uvw: (Vec3, Vec3, Vec3),
-    lens_radius: f64,
-    t0: f64,
-    t1: f64,
+    lens_radius: Float,
+    t0: Float,
+    t1: Float,
     debug: bool,
+    /// Size of a pixel in the coordinates `get_ray` takes, `None` when rays are traced without
+    /// differentials
+    pixel: Option<(Float, Float)>,
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         lookfrom: Vec3,
         lookat: Vec3,
         vup: Vec3,
-        vfov: f64,
-        aspect: f64,
-        aperture: f64,
-        focus_dist: f64,
-        t0: f64,
-        t1: f64,
+        vfov: Float,
+        aspect: Float,
+        aperture: Float,
+        focus_dist: Float,
+        t0: Float,
+        t1: Float,
         debug: bool,
     ) -> Self {
-        let theta = vfov * std::f64::consts::PI / 180.0;
+        let theta = vfov * PI / 180.0;
         let half_height = (theta / 2.0).tan();
         let half_width = aspect * half_height;
 
@@ -46,22 +49,63 @@ impl Camera {
             vertical: 2.0 * half_height * focus_dist * v,
             origin: lookfrom,
             lens_radius: aperture / 2.0,
-            uvw: (u, v, w),
             t0,
             t1,
             debug,
+            pixel: None,
         }
     }
 
-    pub fn get_ray(&self, u: f64, v: f64) -> Ray {
+    /// Sets the resolution of the image rendered, so that camera rays carry the differentials
+    /// textures are filtered with
+    pub fn with_resolution(mut self, nx: u32, ny: u32) -> Self {
+        self.pixel = Some((1.0 / nx as Float, 1.0 / ny as Float));
+        self
+    }
+
+    pub fn get_ray(&self, u: Float, v: Float) -> Ray {
         let rd = self.lens_radius * Ray::random_in_unit_disk();
         let offset = u * rd.x() + v * rd.y();
-        let time = self.t0 + rand::thread_rng().gen::<f64>() * (self.t1 - self.t0);
-        Ray::with_values(
-            self.origin + offset,
-            self.llc + u * self.horizontal + v * self.vertical - self.origin,
-            Some(time),
-            self.debug,
-        )
+        let time = self.t0 + rand::thread_rng().gen::<Float>() * (self.t1 - self.t0);
+        let origin = self.origin + offset;
+        // NOTE: Rays through every point on the lens meet on the plane in focus
+        let direction = self.llc + u * self.horizontal + v * self.vertical - origin;
+        let mut ray = Ray::with_values(origin, direction, Some(time), self.debug);
+
+        // NOTE: The neighbouring rays leave through the same point on the lens as the main ray
+        ray.set_differential(self.pixel.map(|(du, dv)| RayDifferential {
+            rx_origin: origin,
+            rx_direction: direction + du * self.horizontal,
+            ry_origin: origin,
+            ry_direction: direction + dv * self.vertical,
+        }));
+        ray
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_through_the_lens_meet_on_the_plane_in_focus() {
+        let camera = Camera::new(
+            Vec3::with_values(0.0, 0.0, -4.0),
+            Vec3::new(),
+            Vec3::with_values(0.0, 1.0, 0.0),
+            40.0,
+            1.0,
+            2.0,
+            4.0,
+            0.0,
+            1.0,
+            false,
+        );
+        let focus = |ray: &Ray| ray.point_at_param(-ray.origin().z() / ray.direction().z());
+        let first = camera.get_ray(0.3, 0.6);
+        for _ in 0..16 {
+            let ray = camera.get_ray(0.3, 0.6);
+            assert!((focus(&ray) - focus(&first)).len() < 1e-6);
+        }
     }
 }
@@ -0,0 +1,89 @@
+use crate::vec3::{Float, Vec3};
+
+/// Function decodes a value encoded with the sRGB transfer function to linear light
+pub fn srgb_to_linear(value: Float) -> Float {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Function encodes linear light with the sRGB transfer function, the inverse of
+/// `srgb_to_linear`
+pub fn linear_to_srgb(value: Float) -> Float {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Function converts a linear color to the eight bit sRGB channels of an output image, clipping
+/// everything outside of the gamut
+pub fn encode_srgb(color: Vec3) -> (u8, u8, u8) {
+    let encode = |value: Float| (255.0 * linear_to_srgb(value.clamp(0.0, 1.0)) + 0.5) as u8;
+    (encode(color.x()), encode(color.y()), encode(color.z()))
+}
+
+/// Enum lists the color spaces textures can be stored in. Rendering happens in linear light with
+/// the sRGB primaries, which all of them are converted to when loading.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+    /// Gamma encoded sRGB, the space of nearly all eight bit images
+    Srgb,
+    /// Linear light with the sRGB primaries, which is also how data such as normal, bump and
+    /// roughness maps has to be read so that it stays untouched
+    Linear,
+    /// Linear light with the wide gamut AP1 primaries of ACEScg
+    AcesCg,
+}
+
+impl ColorSpace {
+    /// Converts a color stored in this space to the linear working space
+    pub fn to_linear(self, color: Vec3) -> Vec3 {
+        match self {
+            ColorSpace::Srgb => Vec3::with_values(
+                srgb_to_linear(color.x()),
+                srgb_to_linear(color.y()),
+                srgb_to_linear(color.z()),
+            ),
+            ColorSpace::Linear => color,
+            // NOTE: Includes the Bradford adaptation from the D60 white point of ACES to D65
+            ColorSpace::AcesCg => Vec3::with_values(
+                1.705_051 * color.x() - 0.621_792 * color.y() - 0.083_259 * color.z(),
+                -0.130_257 * color.x() + 1.140_805 * color.y() - 0.010_548 * color.z(),
+                -0.024_003 * color.x() - 0.128_969 * color.y() + 1.152_972 * color.z(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_transfer_functions_invert_each_other() {
+        for i in 0..=100 {
+            let value = i as Float / 100.0;
+            assert!((linear_to_srgb(srgb_to_linear(value)) - value).abs() < 1e-5);
+        }
+        // NOTE: Middle gray of an image is far darker in linear light
+        assert!((srgb_to_linear(0.5) - 0.214).abs() < 1e-3);
+        assert_eq!(encode_srgb(Vec3::with_values(0.0, 1.0, 2.0)), (0, 255, 255));
+        for i in 0..=255 {
+            let value = srgb_to_linear(i as Float / 255.0);
+            assert_eq!(encode_srgb(Vec3::with_values(value, 0.0, 0.0)).0, i as u8);
+        }
+    }
+
+    #[test]
+    fn white_stays_white_in_every_space() {
+        let white = Vec3::with_values(1.0, 1.0, 1.0);
+        for &space in [ColorSpace::Srgb, ColorSpace::Linear, ColorSpace::AcesCg].iter() {
+            let linear = space.to_linear(white);
+            assert!((linear - white).len() < 1e-5, "{:?} {:?}", space, linear);
+        }
+    }
+}
@@ -1,25 +1,22 @@
 use crate::aabb::Aabb;
-use crate::material::{Blank, Lambertian, Material};
-use crate::ray::Ray;
-use crate::texture::*;
-use crate::vec3::Vec3;
-use dyn_clone::DynClone;
+use crate::material::Material;
+use crate::ray::offset_ray_origin;
+use crate::ray::{Ray, RayDifferential};
+use crate::texture::{Footprint, Texture};
+use crate::vec3::{gamma, Float, Onb, Vec3, PI};
 use rand::Rng;
 use std::fmt::Debug as DebugTrait;
 
-/// Trait that most be implemented for any objects that a ray can hit
-pub trait Hitable: DynClone + Send + DebugTrait {
+/// Trait that most be implemented for any objects that a ray can hit. Objects are immutable once
+/// built so that a single world can be shared between all render threads.
+pub trait Hitable: Send + Sync + DebugTrait {
     /// Method hit checks whether a incoming ray hits the current object, if it does it returns
-    /// true and the hit record gets updated
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material);
+    /// a hit record holding a reference to the material that was hit
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>>;
     /// Method replaces the bounding box passed in with the bounding box of the object
-    fn bounding_box(&self, t0: f64, t1: f64, bounding_box: &mut Aabb) -> bool;
-    /// Returns a reference to the material of the object
-    fn get_material(&self) -> &dyn Material;
+    fn bounding_box(&self, t0: Float, t1: Float, bounding_box: &mut Aabb) -> bool;
 }
 
-dyn_clone::clone_trait_object!(Hitable);
-
 /// Function computes the bounding box of two bounding boxes.
 pub fn surrounding_box(box0: &Aabb, box1: &Aabb) -> Aabb {
     let small = Vec3::with_values(
@@ -36,57 +33,182 @@ pub fn surrounding_box(box0: &Aabb, box1: &Aabb) -> Aabb {
     Aabb::new(small, big)
 }
 
-/// Struct represents a ray hit record that is later used to check and calculate pixel values
-#[derive(Debug)]
-pub struct HitRecord {
-    pub u: f64,
-    pub v: f64,
-    pub t: f64,
+/// Struct represents a ray hit record that is later used to check and calculate pixel values.
+/// The material is borrowed from the object that was hit, so recording a hit never allocates.
+#[derive(Clone, Copy, Debug)]
+pub struct HitRecord<'a> {
+    pub u: Float,
+    pub v: Float,
+    pub t: Float,
     pub p: Vec3,
+    /// Conservative bound on the absolute floating point error of `p`
+    pub p_error: Vec3,
+    /// Surface normal, always facing against the incoming ray
     pub normal: Vec3,
-    pub material: Box<dyn Material>,
-}
+    /// Partial derivatives of the hit point with respect to `u` and `v`, spanning the tangent
+    /// plane that normal and bump maps are applied in
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    /// Offsets from the hit point to where the rays through the neighbouring pixels meet the
+    /// tangent plane, zero for rays without differentials
+    pub dpdx: Vec3,
+    pub dpdy: Vec3,
+    /// Texture coordinates the pixel covers around the hit, textures are filtered over it
+    pub footprint: Footprint,
+    /// Whether the ray hit the outside of the surface
+    pub front_face: bool,
+    /// Index of refraction of the medium surrounding the object that was hit, vacuum unless the
+    /// object sits inside another medium
+    pub surrounding_ior: Float,
+    /// Wavelength in nanometers the path is traced at, `None` for paths carrying all colors
+    pub wavelength: Option<Float>,
+    pub material: &'a dyn Material,
+}
+
+impl<'a> HitRecord<'a> {
+    /// Creates a record for a hit at `t` along the ray, orienting the outward normal of the
+    /// surface so that it faces against the ray. The partial derivatives default to an arbitrary
+    /// tangent frame until the object that was hit fills them in.
+    pub fn new(
+        r: &Ray,
+        t: Float,
+        outward_normal: Vec3,
+        uv: (Float, Float),
+        material: &'a dyn Material,
+    ) -> Self {
+        let front_face = r.direction().dot(outward_normal) < 0.0;
+        let frame = Onb::from_w(outward_normal);
 
-impl HitRecord {
-    pub fn new() -> Self {
         Self {
-            u: 0.0,
-            v: 0.0,
-            t: 0.0,
-            p: Vec3::new(),
-            normal: Vec3::new(),
-            material: Box::new(Lambertian::new(SolidTexture::new(Vec3::new()))),
+            u: uv.0,
+            v: uv.1,
+            t,
+            p: r.point_at_param(t),
+            p_error: gamma(3) * (r.origin().abs() + (t * r.direction()).abs()),
+            normal: if front_face {
+                outward_normal
+            } else {
+                -outward_normal
+            },
+            dpdu: frame.u(),
+            dpdv: frame.v(),
+            dpdx: Vec3::new(),
+            dpdy: Vec3::new(),
+            footprint: Footprint::default(),
+            front_face,
+            surrounding_ior: 1.0,
+            wavelength: None,
+            material,
         }
     }
 
-    pub fn update(&mut self, rhs: &HitRecord) {
-        self.t = rhs.t;
-        self.p = rhs.p;
-        self.u = rhs.u;
-        self.v = rhs.v;
-        self.normal = rhs.normal;
-        self.material = dyn_clone::clone_box(&*rhs.material);
+    /// Spawns a ray leaving the hit point in `direction`, offset so that it can not intersect the
+    /// surface it leaves again. The ray stays inside the same media as `ray_in`.
+    pub fn spawn_ray(&self, ray_in: &Ray, direction: Vec3) -> Ray {
+        let mut ray = Ray::with_values(
+            offset_ray_origin(self.p, self.p_error, self.normal, direction),
+            direction,
+            Some(ray_in.time()),
+            ray_in.debug(),
+        );
+        ray.set_media(*ray_in.media());
+        ray.set_wavelength(ray_in.wavelength());
+        ray
+    }
+
+    /// Spawns a ray leaving the hit point in `direction` after a specular bounce, which keeps the
+    /// differentials of `ray_in` going. The offsets between the directions get mirrored for
+    /// reflections and carried over unchanged for transmissions, treating the surface as locally
+    /// flat. That underestimates the footprint behind curved mirrors, which only means less
+    /// filtering there.
+    pub fn spawn_specular_ray(&self, ray_in: &Ray, direction: Vec3) -> Ray {
+        let mut ray = self.spawn_ray(ray_in, direction);
+        if let Some(differential) = ray_in.differential() {
+            let d = ray_in.direction().unit_vector();
+            let wi = direction.unit_vector();
+            let reflected = wi.dot(self.normal) > 0.0;
+            let bend = |neighbour: Vec3| {
+                let delta = neighbour.unit_vector() - d;
+                if reflected {
+                    wi + delta - 2.0 * delta.dot(self.normal) * self.normal
+                } else {
+                    wi + delta
+                }
+            };
+            ray.set_differential(Some(RayDifferential {
+                rx_origin: self.p + self.dpdx,
+                rx_direction: bend(differential.rx_direction),
+                ry_origin: self.p + self.dpdy,
+                ry_direction: bend(differential.ry_direction),
+            }));
+        }
+        ray
     }
-}
 
-impl Default for HitRecord {
-    fn default() -> Self {
-        Self::new()
+    /// Intersects the differentials of `ray` with the tangent plane at the hit and derives how
+    /// far the texture coordinates change from one pixel to the next, see PBR 10.1.1
+    pub fn compute_differentials(&mut self, ray: &Ray) {
+        let differential = match ray.differential() {
+            Some(differential) => differential,
+            None => return,
+        };
+
+        let n = self.normal;
+        let plane = |origin: Vec3, direction: Vec3| {
+            let t = n.dot(self.p - origin) / n.dot(direction);
+            if t.is_finite() {
+                Some(origin + t * direction - self.p)
+            } else {
+                None
+            }
+        };
+        let (dpdx, dpdy) = match (
+            plane(differential.rx_origin, differential.rx_direction),
+            plane(differential.ry_origin, differential.ry_direction),
+        ) {
+            (Some(dpdx), Some(dpdy)) => (dpdx, dpdy),
+            _ => return,
+        };
+        self.dpdx = dpdx;
+        self.dpdy = dpdy;
+
+        // NOTE: The offsets are solved for in the two dimensions the normal is least aligned
+        // with, which keeps the system well conditioned
+        let (a, b) = if n.x().abs() > n.y().abs() && n.x().abs() > n.z().abs() {
+            (1, 2)
+        } else if n.y().abs() > n.z().abs() {
+            (0, 2)
+        } else {
+            (0, 1)
+        };
+        let det = self.dpdu[a] * self.dpdv[b] - self.dpdv[a] * self.dpdu[b];
+        if det.abs() < 1e-12 {
+            return;
+        }
+        let solve = |offset: Vec3| {
+            let du = (self.dpdv[b] * offset[a] - self.dpdv[a] * offset[b]) / det;
+            let dv = (self.dpdu[a] * offset[b] - self.dpdu[b] * offset[a]) / det;
+            (du, dv)
+        };
+        let (dudx, dvdx) = solve(dpdx);
+        let (dudy, dvdy) = solve(dpdy);
+        self.footprint = Footprint {
+            dudx,
+            dvdx,
+            dudy,
+            dvdy,
+        };
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct HitableList {
     list: Vec<Box<dyn Hitable>>,
-    _m: Box<dyn Material>,
 }
 
 impl HitableList {
     pub fn new() -> Self {
-        Self {
-            list: Vec::new(),
-            _m: Box::new(Blank::new()),
-        }
+        Self { list: Vec::new() }
     }
 
     pub fn put(&mut self, object: Box<dyn Hitable>) {
@@ -101,43 +223,34 @@ impl Default for HitableList {
 }
 
 impl Hitable for HitableList {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
-        let mut hit_anything = false;
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
         let mut closest_so_far = t_max;
-        let mut record = HitRecord::new();
-        let mut material_ptr = self.list[0].get_material();
+        let mut record = None;
 
         for i in self.list.iter() {
-            if i.hit(r, t_min, closest_so_far, &mut record).0 {
-                hit_anything = true;
-                closest_so_far = record.t;
-                rec.update(&record);
-                material_ptr = i.get_material();
+            if let Some(rec) = i.hit(r, t_min, closest_so_far) {
+                closest_so_far = rec.t;
+                record = Some(rec);
             }
         }
-        (hit_anything, material_ptr)
-    }
-
-    fn get_material(&self) -> &dyn Material {
-        // NOTE: only ever used by BoxObject
-        self.list[0].get_material()
+        record
     }
 
-    fn bounding_box(&self, t0: f64, t1: f64, bounding_box: &mut Aabb) -> bool {
+    fn bounding_box(&self, t0: Float, t1: Float, bounding_box: &mut Aabb) -> bool {
         if self.list.is_empty() {
             return false;
         }
 
         let mut temp_box = Aabb::new(Vec3::new(), Vec3::new());
-        if !self.list[0].bounding_box(t0, t1, &mut temp_box) {
-            std::mem::replace(bounding_box, temp_box.clone());
+        if self.list[0].bounding_box(t0, t1, &mut temp_box) {
+            *bounding_box = temp_box.clone();
         } else {
             return false;
         };
 
         for object in self.list.iter() {
             if object.bounding_box(t0, t1, &mut temp_box) {
-                std::mem::replace(bounding_box, surrounding_box(bounding_box, &temp_box));
+                *bounding_box = surrounding_box(bounding_box, &temp_box);
             } else {
                 return false;
             }
@@ -148,13 +261,13 @@ impl Hitable for HitableList {
 
 #[derive(Clone, Debug)]
 pub struct Sphere {
-    radius: f64,
+    radius: Float,
     center: Vec3,
     pub material: Box<dyn Material>,
 }
 
 impl Sphere {
-    pub fn with_values(center: Vec3, radius: f64, material: Box<dyn Material>) -> Self {
+    pub fn with_values(center: Vec3, radius: Float, material: Box<dyn Material>) -> Self {
         Self {
             radius,
             center,
@@ -162,81 +275,133 @@ impl Sphere {
         }
     }
 
-    pub fn get_sphere_uv(p: Vec3) -> (f64, f64) {
+    pub fn get_sphere_uv(p: Vec3) -> (Float, Float) {
         let phi = p.z().atan2(p.x());
         let theta = p.y().asin();
 
-        let u = 1.0 - (phi + std::f64::consts::PI) / (2.0 * std::f64::consts::PI);
-        let v = (theta + std::f64::consts::PI / 2.0) / std::f64::consts::PI;
+        let u = 1.0 - (phi + PI) / (2.0 * PI);
+        let v = (theta + PI / 2.0) / PI;
 
         (u, v)
     }
-}
 
-impl Hitable for Sphere {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
-        let oc = r.origin() - self.center;
-        let a = r.direction().dot(r.direction());
+    /// Returns the partial derivatives of the point with the outward `normal` on a sphere of
+    /// `radius` with respect to the coordinates of `get_sphere_uv`, `None` at the poles where they
+    /// vanish
+    pub fn get_sphere_partials(normal: Vec3, radius: Float) -> Option<(Vec3, Vec3)> {
+        let cos_theta = (normal.x() * normal.x() + normal.z() * normal.z()).sqrt();
+        if cos_theta < 1e-6 {
+            return None;
+        }
+
+        let dpdu = 2.0 * PI * radius * Vec3::with_values(normal.z(), 0.0, -normal.x());
+        let dpdv = PI
+            * radius
+            * Vec3::with_values(
+                -normal.y() * normal.x() / cos_theta,
+                cos_theta,
+                -normal.y() * normal.z() / cos_theta,
+            );
+        Some((dpdu, dpdv))
+    }
+
+    /// Intersects a ray with a sphere. The discriminant is computed from the distance between
+    /// the ray and the center of the sphere and the roots with the numerically stable form of the
+    /// quadratic formula, so that small or distant spheres stay watertight in single precision.
+    pub fn hit_sphere<'a>(
+        center: Vec3,
+        radius: Float,
+        r: &Ray,
+        t_min: Float,
+        t_max: Float,
+        material: &'a dyn Material,
+    ) -> Option<HitRecord<'a>> {
+        let oc = r.origin() - center;
+        let a = r.direction().squared_len();
         let b = oc.dot(r.direction());
-        let c = oc.dot(oc) - self.radius * self.radius;
-        let d = b * b - a * c;
-
-        if d > 0.0 {
-            let temp = (-b - d.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = r.point_at_param(rec.t);
-                let (u, v) = Self::get_sphere_uv((rec.p - self.center) / self.radius);
-                rec.u = u;
-                rec.v = v;
-                rec.normal = (rec.p - self.center) / self.radius;
-                return (true, self.material.as_ref());
-            }
+        let c = oc.squared_len() - radius * radius;
+        let l = oc - (b / a) * r.direction();
+        let d = a * (radius * radius - l.squared_len());
 
-            let temp = (-b + d.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = r.point_at_param(rec.t);
-                let (u, v) = Self::get_sphere_uv((rec.p - self.center) / self.radius);
-                rec.u = u;
-                rec.v = v;
-                rec.normal = (rec.p - self.center) / self.radius;
-                return (true, self.material.as_ref());
-            }
+        if d < 0.0 {
+            return None;
         }
-        (false, self.material.as_ref())
-    }
 
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
+        let q = -(b + d.sqrt().copysign(b));
+        let (t0, t1) = if q / a < c / q {
+            (q / a, c / q)
+        } else {
+            (c / q, q / a)
+        };
+
+        // NOTE: Roots are only accepted once their error bound clears `t_min`, which keeps rays
+        // spawned off the surface from hitting it again
+        let t_error = gamma(7) * (b.abs() + d.sqrt()) / a;
+        let temp = if t0 < t_max && t0 - t_error > t_min {
+            t0
+        } else if t1 < t_max && t1 - t_error > t_min {
+            t1
+        } else {
+            return None;
+        };
+
+        // NOTE: A negative radius turns the sphere inside out, which is used for hollow glass
+        let direction = (r.point_at_param(temp) - center).unit_vector();
+        let outward_normal = if radius < 0.0 { -direction } else { direction };
+        let mut rec = HitRecord::new(
+            r,
+            temp,
+            outward_normal,
+            Self::get_sphere_uv(outward_normal),
+            material,
+        );
+        // NOTE: Reproject the hit point onto the surface to remove the error accumulated by
+        // evaluating the ray at `t`
+        rec.p = center + radius.abs() * direction;
+        rec.p_error = gamma(5) * (radius * direction).abs() + gamma(1) * rec.p.abs();
+        if let Some((dpdu, dpdv)) = Self::get_sphere_partials(outward_normal, radius.abs()) {
+            rec.dpdu = dpdu;
+            rec.dpdv = dpdv;
+        }
+        Some(rec)
     }
+}
 
-    fn bounding_box(&self, _: f64, _: f64, bounding_box: &mut Aabb) -> bool {
-        std::mem::replace(
-            bounding_box,
-            Aabb::new(
-                self.center - Vec3::with_values(self.radius, self.radius, self.radius),
-                self.center + Vec3::with_values(self.radius, self.radius, self.radius),
-            ),
+impl Hitable for Sphere {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        Self::hit_sphere(
+            self.center,
+            self.radius,
+            r,
+            t_min,
+            t_max,
+            self.material.as_ref(),
+        )
+    }
+
+    fn bounding_box(&self, _: Float, _: Float, bounding_box: &mut Aabb) -> bool {
+        *bounding_box = Aabb::new(
+            self.center - Vec3::with_values(self.radius, self.radius, self.radius),
+            self.center + Vec3::with_values(self.radius, self.radius, self.radius),
         );
         true
     }
 }
 #[derive(Clone, Debug)]
 pub struct MovingSphere {
-    radius: f64,
+    radius: Float,
     centers: (Vec3, Vec3),
     pub material: Box<dyn Material>,
-    time0: f64,
-    time1: f64,
+    time0: Float,
+    time1: Float,
 }
 
 impl MovingSphere {
     pub fn with_values(
         centers: (Vec3, Vec3),
-        time0: f64,
-        time1: f64,
-        radius: f64,
+        time0: Float,
+        time1: Float,
+        radius: Float,
         material: Box<dyn Material>,
     ) -> Self {
         Self {
@@ -248,45 +413,25 @@ impl MovingSphere {
         }
     }
 
-    pub fn center(&self, time: f64) -> Vec3 {
+    pub fn center(&self, time: Float) -> Vec3 {
         self.centers.0
             + ((time - self.time0) / (self.time1 - self.time0)) * (self.centers.1 - self.centers.0)
     }
 }
 
 impl Hitable for MovingSphere {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
-        let oc = r.origin() - self.center(r.time());
-        let a = r.direction().dot(r.direction());
-        let b = oc.dot(r.direction());
-        let c = oc.dot(oc) - self.radius * self.radius;
-        let d = b * b - a * c;
-
-        if d > 0.0 {
-            let temp = (-b - d.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = r.point_at_param(rec.t);
-                rec.normal = (rec.p - self.center(r.time())) / self.radius;
-                return (true, self.material.as_ref());
-            }
-
-            let temp = (-b + d.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = r.point_at_param(rec.t);
-                rec.normal = (rec.p - self.center(r.time())) / self.radius;
-                return (true, self.material.as_ref());
-            }
-        }
-        (false, self.material.as_ref())
-    }
-
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
-    }
-
-    fn bounding_box(&self, t0: f64, t1: f64, bounding_box: &mut Aabb) -> bool {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        Sphere::hit_sphere(
+            self.center(r.time()),
+            self.radius,
+            r,
+            t_min,
+            t_max,
+            self.material.as_ref(),
+        )
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float, bounding_box: &mut Aabb) -> bool {
         let box0 = Aabb::new(
             self.center(t0) - Vec3::with_values(self.radius, self.radius, self.radius),
             self.center(t0) + Vec3::with_values(self.radius, self.radius, self.radius),
@@ -297,28 +442,30 @@ impl Hitable for MovingSphere {
             self.center(t1) + Vec3::with_values(self.radius, self.radius, self.radius),
         );
 
-        std::mem::replace(bounding_box, surrounding_box(&box0, &box1));
+        *bounding_box = surrounding_box(&box0, &box1);
         true
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct BvhNode {
     left: Box<dyn Hitable>,
     right: Box<dyn Hitable>,
     bounding_box: Aabb,
-    material: Box<dyn Material>,
 }
 
 impl BvhNode {
-    pub fn new(
-        nodes: &mut Vec<Box<dyn Hitable>>,
-        material: Box<dyn Material>,
-        t0: f64,
-        t1: f64,
-    ) -> Self {
+    pub fn new(mut nodes: Vec<Box<dyn Hitable>>, t0: Float, t1: Float) -> Self {
+        if nodes.is_empty() {
+            return Self {
+                left: Box::new(HitableList::new()),
+                right: Box::new(HitableList::new()),
+                bounding_box: Aabb::new(Vec3::new(), Vec3::new()),
+            };
+        }
+
         let mut rng = rand::thread_rng();
-        let axis = (3.0 * rng.gen::<f64>()) as i32;
+        let axis = (3.0 * rng.gen::<Float>()) as i32;
 
         if axis == 0 {
             nodes.sort_unstable_by(|b, a| {
@@ -360,10 +507,9 @@ impl BvhNode {
             nodes[0].bounding_box(t0, t1, &mut box_left);
 
             Self {
-                left: dyn_clone::clone_box(&*nodes[0]),
-                right: dyn_clone::clone_box(&*nodes[0]),
+                left: nodes.remove(0),
+                right: Box::new(HitableList::new()),
                 bounding_box: box_left,
-                material: material,
             }
         } else if nodes.len() == 2 {
             let mut box_left = Aabb::new(Vec3::new(), Vec3::new());
@@ -371,22 +517,17 @@ impl BvhNode {
             nodes[0].bounding_box(t0, t1, &mut box_left);
             nodes[1].bounding_box(t0, t1, &mut box_right);
 
+            let right = nodes.remove(1);
             Self {
-                left: dyn_clone::clone_box(&*nodes[0]),
-                right: dyn_clone::clone_box(&*nodes[1]),
+                left: nodes.remove(0),
+                right,
                 bounding_box: surrounding_box(&box_left, &box_right),
-                material: material,
             }
         } else {
             let mut box_left = Aabb::new(Vec3::new(), Vec3::new());
             let mut box_right = Aabb::new(Vec3::new(), Vec3::new());
-            let left = BvhNode::new(
-                &mut nodes.split_off(nodes.len() / 2),
-                dyn_clone::clone_box(&*material),
-                t0,
-                t1,
-            );
-            let right = BvhNode::new(nodes, dyn_clone::clone_box(&*material), t0, t1);
+            let left = BvhNode::new(nodes.split_off(nodes.len() / 2), t0, t1);
+            let right = BvhNode::new(nodes, t0, t1);
 
             left.bounding_box(t0, t1, &mut box_left);
             right.bounding_box(t0, t1, &mut box_right);
@@ -395,49 +536,26 @@ impl BvhNode {
                 left: Box::new(left),
                 right: Box::new(right),
                 bounding_box: surrounding_box(&box_left, &box_right),
-                material: material,
             }
         }
     }
 }
 
 impl Hitable for BvhNode {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
-        if self.bounding_box.hit(r, t_min, t_max) {
-            let mut rec_right = HitRecord::new();
-            let mut rec_left = HitRecord::new();
-
-            let hit_left = self.left.hit(r, t_min, t_max, &mut rec_left).0;
-            let hit_right = self.right.hit(r, t_min, t_max, &mut rec_right).0;
-
-            if hit_left && hit_right {
-                if rec_left.t < rec_right.t {
-                    std::mem::replace(rec, rec_left);
-                    return (true, self.left.get_material());
-                } else {
-                    std::mem::replace(rec, rec_right);
-                    return (true, self.right.get_material());
-                }
-            } else if hit_left {
-                std::mem::replace(rec, rec_left);
-                return (true, self.left.get_material());
-            } else if hit_right {
-                std::mem::replace(rec, rec_right);
-                return (true, self.right.get_material());
-            } else {
-                return (false, self.left.get_material());
-            }
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        if !self.bounding_box.hit(r, t_min, t_max) {
+            return None;
         }
 
-        (false, self.left.get_material())
-    }
+        let hit_left = self.left.hit(r, t_min, t_max);
+        let closest_so_far = hit_left.map_or(t_max, |rec| rec.t);
+        let hit_right = self.right.hit(r, t_min, closest_so_far);
 
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
+        hit_right.or(hit_left)
     }
 
-    fn bounding_box(&self, _: f64, _: f64, bounding_box: &mut Aabb) -> bool {
-        std::mem::replace(bounding_box, self.bounding_box.clone());
+    fn bounding_box(&self, _: Float, _: Float, bounding_box: &mut Aabb) -> bool {
+        *bounding_box = self.bounding_box.clone();
         true
     }
 }
@@ -445,15 +563,15 @@ impl Hitable for BvhNode {
 #[derive(Clone, Debug)]
 pub struct RectSliceXy {
     material: Box<dyn Material>,
-    x0: f64,
-    x1: f64,
-    y0: f64,
-    y1: f64,
-    k: f64,
+    x0: Float,
+    x1: Float,
+    y0: Float,
+    y1: Float,
+    k: Float,
 }
 
 impl RectSliceXy {
-    pub fn new(material: Box<dyn Material>, params: (f64, f64, f64, f64, f64)) -> Self {
+    pub fn new(material: Box<dyn Material>, params: (Float, Float, Float, Float, Float)) -> Self {
         let (x0, x1, y0, y1, k) = params;
 
         Self {
@@ -468,39 +586,42 @@ impl RectSliceXy {
 }
 
 impl Hitable for RectSliceXy {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
         let t = (self.k - r.origin().z()) / r.direction().z();
-        if t < t_min || t > t_max {
-            return (false, self.get_material());
+        if !(t > t_min && t < t_max) {
+            return None;
         }
 
         let x = r.origin().x() + t * r.direction().x();
         let y = r.origin().y() + t * r.direction().y();
 
         if x < self.x0 || x > self.x1 || y < self.y0 || y > self.y1 {
-            return (false, self.get_material());
+            return None;
         }
 
-        rec.u = (x - self.x0) / (self.x1 - self.x0);
-        rec.v = (y - self.y0) / (self.y1 - self.y0);
-        rec.t = t;
-        rec.p = r.point_at_param(t);
-        rec.normal = Vec3::with_values(0.0, 0.0, 1.0);
-
-        (true, self.get_material())
-    }
-
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
-    }
-
-    fn bounding_box(&self, _: f64, _: f64, bounding_box: &mut Aabb) -> bool {
-        std::mem::replace(
-            bounding_box,
-            Aabb::new(
-                Vec3::with_values(self.x0, self.y0, self.k - 0.0001),
-                Vec3::with_values(self.x1, self.y1, self.k + 0.0001),
+        let mut rec = HitRecord::new(
+            r,
+            t,
+            Vec3::with_values(0.0, 0.0, 1.0),
+            (
+                (x - self.x0) / (self.x1 - self.x0),
+                (y - self.y0) / (self.y1 - self.y0),
             ),
+            self.material.as_ref(),
+        );
+        // NOTE: The hit point lies exactly on the plane of the rectangle, so rays leaving it are
+        // only rejected by requiring `t` to be strictly greater than `t_min`
+        rec.p = Vec3::with_values(x, y, self.k);
+        rec.p_error = Vec3::with_values(rec.p_error.x(), rec.p_error.y(), 0.0);
+        rec.dpdu = Vec3::with_values(self.x1 - self.x0, 0.0, 0.0);
+        rec.dpdv = Vec3::with_values(0.0, self.y1 - self.y0, 0.0);
+        Some(rec)
+    }
+
+    fn bounding_box(&self, _: Float, _: Float, bounding_box: &mut Aabb) -> bool {
+        *bounding_box = Aabb::new(
+            Vec3::with_values(self.x0, self.y0, self.k - 0.0001),
+            Vec3::with_values(self.x1, self.y1, self.k + 0.0001),
         );
         true
     }
@@ -509,15 +630,15 @@ impl Hitable for RectSliceXy {
 #[derive(Clone, Debug)]
 pub struct RectSliceXz {
     material: Box<dyn Material>,
-    x0: f64,
-    x1: f64,
-    z0: f64,
-    z1: f64,
-    k: f64,
+    x0: Float,
+    x1: Float,
+    z0: Float,
+    z1: Float,
+    k: Float,
 }
 
 impl RectSliceXz {
-    pub fn new(material: Box<dyn Material>, params: (f64, f64, f64, f64, f64)) -> Self {
+    pub fn new(material: Box<dyn Material>, params: (Float, Float, Float, Float, Float)) -> Self {
         let (x0, x1, z0, z1, k) = params;
 
         Self {
@@ -532,39 +653,42 @@ impl RectSliceXz {
 }
 
 impl Hitable for RectSliceXz {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
         let t = (self.k - r.origin().y()) / r.direction().y();
-        if t < t_min || t > t_max {
-            return (false, self.get_material());
+        if !(t > t_min && t < t_max) {
+            return None;
         }
 
         let x = r.origin().x() + t * r.direction().x();
         let z = r.origin().z() + t * r.direction().z();
 
         if x < self.x0 || x > self.x1 || z < self.z0 || z > self.z1 {
-            return (false, self.get_material());
+            return None;
         }
 
-        rec.u = (x - self.x0) / (self.x1 - self.x0);
-        rec.v = (z - self.z0) / (self.z1 - self.z0);
-        rec.t = t;
-        rec.p = r.point_at_param(t);
-        rec.normal = Vec3::with_values(0.0, 1.0, 0.0);
-
-        (true, self.get_material())
-    }
-
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
-    }
-
-    fn bounding_box(&self, _: f64, _: f64, bounding_box: &mut Aabb) -> bool {
-        std::mem::replace(
-            bounding_box,
-            Aabb::new(
-                Vec3::with_values(self.x0, self.k - 0.0001, self.z0),
-                Vec3::with_values(self.x1, self.k + 0.0001, self.z1),
+        let mut rec = HitRecord::new(
+            r,
+            t,
+            Vec3::with_values(0.0, 1.0, 0.0),
+            (
+                (x - self.x0) / (self.x1 - self.x0),
+                (z - self.z0) / (self.z1 - self.z0),
             ),
+            self.material.as_ref(),
+        );
+        // NOTE: The hit point lies exactly on the plane of the rectangle, so rays leaving it are
+        // only rejected by requiring `t` to be strictly greater than `t_min`
+        rec.p = Vec3::with_values(x, self.k, z);
+        rec.p_error = Vec3::with_values(rec.p_error.x(), 0.0, rec.p_error.z());
+        rec.dpdu = Vec3::with_values(self.x1 - self.x0, 0.0, 0.0);
+        rec.dpdv = Vec3::with_values(0.0, 0.0, self.z1 - self.z0);
+        Some(rec)
+    }
+
+    fn bounding_box(&self, _: Float, _: Float, bounding_box: &mut Aabb) -> bool {
+        *bounding_box = Aabb::new(
+            Vec3::with_values(self.x0, self.k - 0.0001, self.z0),
+            Vec3::with_values(self.x1, self.k + 0.0001, self.z1),
         );
         true
     }
@@ -573,15 +697,15 @@ impl Hitable for RectSliceXz {
 #[derive(Clone, Debug)]
 pub struct RectSliceYz {
     material: Box<dyn Material>,
-    y0: f64,
-    y1: f64,
-    z0: f64,
-    z1: f64,
-    k: f64,
+    y0: Float,
+    y1: Float,
+    z0: Float,
+    z1: Float,
+    k: Float,
 }
 
 impl RectSliceYz {
-    pub fn new(material: Box<dyn Material>, params: (f64, f64, f64, f64, f64)) -> Self {
+    pub fn new(material: Box<dyn Material>, params: (Float, Float, Float, Float, Float)) -> Self {
         let (y0, y1, z0, z1, k) = params;
 
         Self {
@@ -596,45 +720,48 @@ impl RectSliceYz {
 }
 
 impl Hitable for RectSliceYz {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
         let t = (self.k - r.origin().x()) / r.direction().x();
-        if t < t_min || t > t_max {
-            return (false, self.get_material());
+        if !(t > t_min && t < t_max) {
+            return None;
         }
 
         let y = r.origin().y() + t * r.direction().y();
         let z = r.origin().z() + t * r.direction().z();
 
         if z < self.z0 || z > self.z1 || y < self.y0 || y > self.y1 {
-            return (false, self.get_material());
+            return None;
         }
 
-        rec.u = (y - self.y0) / (self.y1 - self.y0);
-        rec.v = (z - self.z0) / (self.z1 - self.z0);
-        rec.t = t;
-        rec.p = r.point_at_param(t);
-        rec.normal = Vec3::with_values(1.0, 0.0, 0.0);
-
-        (true, self.get_material())
-    }
-
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
-    }
-
-    fn bounding_box(&self, _: f64, _: f64, bounding_box: &mut Aabb) -> bool {
-        std::mem::replace(
-            bounding_box,
-            Aabb::new(
-                Vec3::with_values(self.k - 0.0001, self.y0, self.z0),
-                Vec3::with_values(self.k + 0.0001, self.y1, self.z1),
+        let mut rec = HitRecord::new(
+            r,
+            t,
+            Vec3::with_values(1.0, 0.0, 0.0),
+            (
+                (y - self.y0) / (self.y1 - self.y0),
+                (z - self.z0) / (self.z1 - self.z0),
             ),
+            self.material.as_ref(),
+        );
+        // NOTE: The hit point lies exactly on the plane of the rectangle, so rays leaving it are
+        // only rejected by requiring `t` to be strictly greater than `t_min`
+        rec.p = Vec3::with_values(self.k, y, z);
+        rec.p_error = Vec3::with_values(0.0, rec.p_error.y(), rec.p_error.z());
+        rec.dpdu = Vec3::with_values(0.0, self.y1 - self.y0, 0.0);
+        rec.dpdv = Vec3::with_values(0.0, 0.0, self.z1 - self.z0);
+        Some(rec)
+    }
+
+    fn bounding_box(&self, _: Float, _: Float, bounding_box: &mut Aabb) -> bool {
+        *bounding_box = Aabb::new(
+            Vec3::with_values(self.k - 0.0001, self.y0, self.z0),
+            Vec3::with_values(self.k + 0.0001, self.y1, self.z1),
         );
         true
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct FlipNormals {
     child: Box<dyn Hitable>,
 }
@@ -646,30 +773,77 @@ impl FlipNormals {
 }
 
 impl Hitable for FlipNormals {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
-        if self.child.hit(r, t_min, t_max, rec).0 {
-            rec.normal = -rec.normal;
-            (true, self.child.get_material())
-        } else {
-            (false, self.child.get_material())
-        }
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        // NOTE: the record normal already faces the ray, so flipping the outward normal only
+        // changes which side of the surface counts as the front
+        self.child.hit(r, t_min, t_max).map(|mut rec| {
+            rec.front_face = !rec.front_face;
+            rec
+        })
     }
 
-    fn bounding_box(&self, t0: f64, t1: f64, bounding_box: &mut Aabb) -> bool {
+    fn bounding_box(&self, t0: Float, t1: Float, bounding_box: &mut Aabb) -> bool {
         self.child.bounding_box(t0, t1, bounding_box)
     }
+}
+
+/// Cutout makes parts of an object transparent following an opacity texture, as for the leaves
+/// of foliage cards, fences or decals. Rays pass straight through transparent parts while
+/// intersecting, hitting whatever lies behind them instead. Parts are opaque where the opacity
+/// reaches the threshold, or with a probability equal to their opacity when there is none.
+#[derive(Debug)]
+pub struct Cutout {
+    child: Box<dyn Hitable>,
+    opacity: Box<dyn Texture>,
+    threshold: Option<Float>,
+}
+
+impl Cutout {
+    pub fn new(
+        child: Box<dyn Hitable>,
+        opacity: Box<dyn Texture>,
+        threshold: Option<Float>,
+    ) -> Self {
+        Self {
+            child,
+            opacity,
+            threshold,
+        }
+    }
 
-    fn get_material(&self) -> &dyn Material {
-        self.child.get_material()
+    fn is_opaque(&self, rec: &HitRecord) -> bool {
+        let opacity = self.opacity.scalar(rec.u, rec.v, rec.p);
+        match self.threshold {
+            Some(threshold) => opacity >= threshold,
+            None => rand::thread_rng().gen::<Float>() < opacity,
+        }
     }
 }
 
-#[derive(Clone, Debug)]
+impl Hitable for Cutout {
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        // NOTE: Every object rejects hits at exactly `t_min`, so searching on from a transparent
+        // hit always moves further along the ray
+        let mut t_min = t_min;
+        loop {
+            let rec = self.child.hit(r, t_min, t_max)?;
+            if self.is_opaque(&rec) {
+                return Some(rec);
+            }
+            t_min = rec.t;
+        }
+    }
+
+    fn bounding_box(&self, t0: Float, t1: Float, bounding_box: &mut Aabb) -> bool {
+        self.child.bounding_box(t0, t1, bounding_box)
+    }
+}
+
+#[derive(Debug)]
 pub struct BoxObject {
     p0: Vec3,
     p1: Vec3,
     hitlist: HitableList,
-    material: Box<dyn Material>,
 }
 
 impl BoxObject {
@@ -701,29 +875,143 @@ impl BoxObject {
         )));
 
         hitlist.put(Box::new(FlipNormals::new(Box::new(RectSliceYz::new(
-            dyn_clone::clone_box(&*material),
+            material,
             (p0.y(), p1.y(), p0.z(), p1.z(), p0.x()),
         )))));
-        Self {
-            p0,
-            p1,
-            hitlist,
-            material,
-        }
+        Self { p0, p1, hitlist }
     }
 }
 
 impl Hitable for BoxObject {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> (bool, &dyn Material) {
-        self.hitlist.hit(r, t_min, t_max, rec)
+    fn hit(&self, r: &Ray, t_min: Float, t_max: Float) -> Option<HitRecord<'_>> {
+        self.hitlist.hit(r, t_min, t_max)
     }
 
-    fn bounding_box(&self, _: f64, _: f64, bounding_box: &mut Aabb) -> bool {
-        std::mem::replace(bounding_box, Aabb::new(self.p0, self.p1));
+    fn bounding_box(&self, _: Float, _: Float, bounding_box: &mut Aabb) -> bool {
+        *bounding_box = Aabb::new(self.p0, self.p1);
         true
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Lambertian;
+    use crate::texture::SolidTexture;
 
-    fn get_material(&self) -> &dyn Material {
-        self.material.as_ref()
+    fn gray(value: Float) -> Box<dyn Texture> {
+        SolidTexture::new(Vec3::with_values(value, value, value))
+    }
+
+    /// Returns a world of a cutout square at z = 1 in front of an opaque one at z = 2
+    fn cutout_world(opacity: Float, threshold: Option<Float>) -> HitableList {
+        let square = |z| {
+            RectSliceXy::new(
+                Box::new(Lambertian::new(gray(0.5))),
+                (-1.0, 1.0, -1.0, 1.0, z),
+            )
+        };
+        let mut world = HitableList::new();
+        world.put(Box::new(Cutout::new(
+            Box::new(square(1.0)),
+            gray(opacity),
+            threshold,
+        )));
+        world.put(Box::new(square(2.0)));
+        world
+    }
+
+    #[test]
+    fn lists_are_bounded_by_all_of_their_objects() {
+        let mut world = cutout_world(1.0, None);
+        world.put(Box::new(BoxObject::new(
+            Vec3::with_values(2.0, 0.0, 0.0),
+            Vec3::with_values(3.0, 4.0, 5.0),
+            Box::new(Lambertian::new(gray(0.5))),
+        )));
+        let mut bounding_box = Aabb::new(Vec3::new(), Vec3::new());
+        assert!(world.bounding_box(0.0, 1.0, &mut bounding_box));
+        assert_eq!(bounding_box.min(), Vec3::with_values(-1.0, -1.0, 0.0));
+        assert_eq!(bounding_box.max(), Vec3::with_values(3.0, 4.0, 5.0));
+    }
+
+    #[test]
+    fn rays_pass_through_transparent_parts_of_cutouts() {
+        let ray = Ray::with_values(Vec3::new(), Vec3::with_values(0.0, 0.0, 1.0), None, false);
+        let hit = |world: &HitableList| world.hit(&ray, 0.0, Float::MAX).unwrap().t;
+
+        assert_eq!(hit(&cutout_world(1.0, None)), 1.0);
+        assert_eq!(hit(&cutout_world(0.0, None)), 2.0);
+        assert_eq!(hit(&cutout_world(0.4, Some(0.5))), 2.0);
+        assert_eq!(hit(&cutout_world(0.6, Some(0.5))), 1.0);
+
+        let world = cutout_world(0.3, None);
+        let samples = 10000;
+        let front = (0..samples).filter(|_| hit(&world) == 1.0).count();
+        let fraction = front as Float / samples as Float;
+        assert!((fraction - 0.3).abs() < 0.03, "{}", fraction);
+    }
+
+    #[test]
+    fn footprints_grow_with_distance_and_follow_mirrors() {
+        let mut ray = Ray::with_values(Vec3::new(), Vec3::with_values(0.0, 0.0, 1.0), None, false);
+        ray.set_differential(Some(RayDifferential {
+            rx_origin: Vec3::new(),
+            rx_direction: Vec3::with_values(0.01, 0.0, 1.0),
+            ry_origin: Vec3::new(),
+            ry_direction: Vec3::with_values(0.0, 0.02, 1.0),
+        }));
+        let material = Lambertian::new(gray(0.5));
+        let square = |z| RectSliceXy::new(Box::new(material.clone()), (-1.0, 1.0, -1.0, 1.0, z));
+
+        // NOTE: The squares are two units wide, so texture coordinates change at half the rate
+        // of the hit points
+        let near = square(1.0);
+        let mut record = near.hit(&ray, 0.0, Float::MAX).unwrap();
+        record.compute_differentials(&ray);
+        let footprint = record.footprint;
+        assert!((footprint.dudx - 0.005).abs() < 1e-9 && footprint.dvdx.abs() < 1e-9);
+        assert!(footprint.dudy.abs() < 1e-9 && (footprint.dvdy - 0.01).abs() < 1e-9);
+
+        // NOTE: Bouncing back to a square at z = -1 makes the path three times as long
+        let reflected = record.spawn_specular_ray(&ray, Vec3::with_values(0.0, 0.0, -1.0));
+        let far = square(-1.0);
+        let mut record = far.hit(&reflected, 0.0, Float::MAX).unwrap();
+        record.compute_differentials(&reflected);
+        assert!((record.footprint.dudx.abs() - 0.015).abs() < 1e-6);
+        assert!((record.footprint.dvdy.abs() - 0.03).abs() < 1e-6);
+
+        // NOTE: Diffuse bounces drop the differentials
+        let scattered = record.spawn_ray(&reflected, Vec3::with_values(0.0, 0.0, 1.0));
+        assert!(scattered.differential().is_none());
+    }
+
+    /// Returns the point on the unit sphere with the coordinates of `Sphere::get_sphere_uv`
+    fn sphere_point(u: Float, v: Float) -> Vec3 {
+        let phi = 2.0 * PI * (1.0 - u) - PI;
+        let theta = PI * v - PI / 2.0;
+        Vec3::with_values(
+            theta.cos() * phi.cos(),
+            theta.sin(),
+            theta.cos() * phi.sin(),
+        )
+    }
+
+    #[test]
+    fn sphere_partials_match_the_uv_mapping() {
+        let delta = 1e-3;
+        for &(u, v) in [(0.1, 0.3), (0.45, 0.5), (0.8, 0.9)].iter() {
+            let p = sphere_point(u, v);
+            let (su, sv) = Sphere::get_sphere_uv(p);
+            assert!((su - u).abs() < 1e-4 && (sv - v).abs() < 1e-4);
+
+            let (dpdu, dpdv) = Sphere::get_sphere_partials(p, 2.0).unwrap();
+            let du =
+                2.0 * (sphere_point(u + delta, v) - sphere_point(u - delta, v)) / (2.0 * delta);
+            let dv =
+                2.0 * (sphere_point(u, v + delta) - sphere_point(u, v - delta)) / (2.0 * delta);
+            assert!((dpdu - du).len() < 1e-3 * du.len(), "{:?} {:?}", dpdu, du);
+            assert!((dpdv - dv).len() < 1e-3 * dv.len(), "{:?} {:?}", dpdv, dv);
+        }
     }
 }
@@ -1,21 +1,33 @@
 pub mod aabb;
 pub mod camera;
+pub mod color;
 pub mod hitable;
 pub mod map;
 pub mod material;
+pub mod medium;
+pub mod microfacet;
+pub mod procedural;
 pub mod ray;
+pub mod spectrum;
 pub mod texture;
+pub mod texture_ops;
+pub mod thin_film;
 pub mod vec3;
 
 use camera::Camera;
 use clap::clap_app;
-use hitable::HitableList;
+use color::encode_srgb;
+use hitable::Hitable;
 use image::{imageops::*, ImageBuffer, Pixel, Rgb};
 use map::MapFile;
 use rand::Rng;
+use std::sync::Arc;
 use std::thread::{spawn, JoinHandle};
 use vec3::*;
 
+/// Rendered pixels of a single thread in linear light, stored column by column
+type RenderResult = Vec<Vec<Vec3>>;
+
 fn main() {
     let matches = clap_app!(raytracer =>
         (version: "0.1")
@@ -62,76 +74,51 @@ fn main() {
 
     map.dump_to_file("current_map.json");
 
-    let world = map.build_world();
+    // NOTE: The world is built once and shared between all threads, it is never cloned.
+    let world = Arc::new(map.build_world());
 
     let mut image = ImageBuffer::new(nx, ny);
-    let mut threads: Vec<JoinHandle<Vec<Vec<(u8, u8, u8)>>>> = Vec::new();
+    let mut threads: Vec<JoinHandle<RenderResult>> = Vec::new();
 
     let camera = Camera::new(
         map.lookfrom.into(),
         map.lookat.into(),
         Vec3::with_values(0.0, 1.0, 0.0),
         40.0,
-        nx as f64 / ny as f64,
+        nx as Float / ny as Float,
         map.aperture,
         map.dist_to_focus,
         0.0,
         1.0,
         debug,
-    );
+    )
+    .with_resolution(nx, ny);
 
     println!("Rendering: {}", map.objects.len());
 
     for _ in 0..thread_count {
         let camera = camera.clone();
-        let world = world.clone();
-        let handle = spawn(move || render(camera, world, nx, ny, ns));
+        let world = Arc::clone(&world);
+        let handle = spawn(move || render(&camera, world.as_ref(), nx, ny, ns));
         threads.push(handle);
     }
 
-    let results: Vec<Vec<Vec<(u8, u8, u8)>>> =
-        threads.drain(0..).map(|x| x.join().unwrap()).collect();
+    let results: Vec<RenderResult> = threads.drain(0..).map(|x| x.join().unwrap()).collect();
 
-    let blank: Vec<Vec<(u8, u8, u8)>> = {
-        let mut x = Vec::new();
-        for _ in 0..nx {
-            let mut y = Vec::new();
-            for _ in 0..ny {
-                y.push((0, 0, 0))
+    // NOTE: Threads are averaged in linear light, the result is only encoded once at the end
+    let mut r = vec![vec![Vec3::new(); nx as usize]; ny as usize];
+    for result in results.iter() {
+        for (column, pixels) in r.iter_mut().zip(result.iter()) {
+            for (pixel, color) in column.iter_mut().zip(pixels.iter()) {
+                *pixel += *color / results.len() as Float;
             }
-            x.push(y)
         }
-        x
-    };
-    let r = results
-        .iter()
-        .fold(blank, |acc: Vec<Vec<(u8, u8, u8)>>, x| {
-            x.iter()
-                .zip(acc.iter())
-                .map(|(a, b)| {
-                    a.iter()
-                        .zip(b.iter())
-                        .map(|(x, y)| {
-                            (
-                                x.0 as u16 + y.0 as u16,
-                                x.1 as u16 + y.1 as u16,
-                                x.2 as u16 + y.2 as u16,
-                            )
-                        })
-                        .map(|x| (x.0 / 2, x.1 / 2, x.2 / 2))
-                        .map(|x| (x.0 as u8, x.1 as u8, x.2 as u8))
-                        .collect::<Vec<(u8, u8, u8)>>()
-                })
-                .collect::<Vec<Vec<(u8, u8, u8)>>>()
-        });
+    }
 
     for (xi, xp) in r.iter().enumerate() {
         for (yi, yp) in xp.iter().enumerate() {
-            image.put_pixel(
-                xi as u32,
-                yi as u32,
-                Rgb::from_channels(yp.0, yp.1, yp.2, 255),
-            );
+            let (ir, ig, ib) = encode_srgb(*yp);
+            image.put_pixel(xi as u32, yi as u32, Rgb::from_channels(ir, ig, ib, 255));
         }
     }
 
@@ -141,13 +128,7 @@ fn main() {
     image.save(outfile).unwrap();
 }
 
-fn render(
-    camera: Camera,
-    mut world: HitableList,
-    nx: u32,
-    ny: u32,
-    ns: u32,
-) -> Vec<Vec<(u8, u8, u8)>> {
+fn render(camera: &Camera, world: &dyn Hitable, nx: u32, ny: u32, ns: u32) -> RenderResult {
     let mut rng = rand::thread_rng();
     let mut result = Vec::new();
     for j in 0..ny {
@@ -156,19 +137,14 @@ fn render(
         for i in 0..nx {
             let mut col = Vec3::new();
             for _ in 0..ns {
-                let u = ((i as f64) + rng.gen::<f64>()) / (nx as f64);
-                let v = (((ny - j) as f64) + rng.gen::<f64>()) / (ny as f64);
+                let u = ((i as Float) + rng.gen::<Float>()) / (nx as Float);
+                let v = (((ny - j) as Float) + rng.gen::<Float>()) / (ny as Float);
                 let ray = camera.get_ray(u, v);
-                col += ray.color(&mut world, 0);
+                col += ray.color(world, 0);
             }
 
-            col /= ns as f64;
-            col = Vec3::with_values(col.x().min(1.0), col.y().min(1.0), col.z().min(1.0));
-
-            let ir = (255.99 * col.x().sqrt()) as u8;
-            let ig = (255.99 * col.y().sqrt()) as u8;
-            let ib = (255.99 * col.z().sqrt()) as u8;
-            column.push((ir, ig, ib));
+            col /= ns as Float;
+            column.push(col);
         }
         result.push(column);
     }
@@ -1,89 +1,546 @@
+use crate::color::ColorSpace as ColorSpaceClass;
 use crate::hitable::HitableList;
 use crate::hitable::{
-    BoxObject, BvhNode, FlipNormals, Hitable, MovingSphere, RectSliceXy, RectSliceXz, RectSliceYz,
-    Sphere,
+    BoxObject, BvhNode, Cutout, FlipNormals, Hitable, MovingSphere, RectSliceXy, RectSliceXz,
+    RectSliceYz, Sphere,
+};
+use crate::material::{
+    Bumped, Coated, ComplexIor as ComplexIorClass, Conductor, Dielectric as DielectricClass,
+    DiffuseLight, Lambertian, LommelSeeliger, Material as MaterialClass, Metal, Mix, OrenNayar,
+    Principled as PrincipledClass, RoughDielectric, Velvet,
+};
+use crate::medium::{Dispersion as DispersionClass, Medium as MediumClass};
+use crate::procedural::{
+    ColorRamp, FbmTexture, Fractal, GradientAxis as GradientAxisClass, GradientTexture,
+    MarbleTexture, WoodTexture, WorleyFeature as WorleyFeatureClass, WorleyTexture,
 };
-use crate::material::{Dielectric, DiffuseLight, Lambertian, Material as MaterialClass, Metal};
 use crate::texture::{
-    CheckerTexture, ImageTexture, NoiseTexture, SolidTexture, Texture as TextureClass,
+    CheckerTexture, Filter as FilterClass, ImageTexture, NoiseTexture, SolidTexture,
+    Texture as TextureClass, UvTransform as UvTransformClass, Wrap as WrapClass,
 };
-use crate::vec3::Vec3;
+use crate::texture_ops::{
+    AdjustedTexture, Adjustment, Arithmetic, ArithmeticTexture, Channel as ChannelClass,
+    ChannelTexture, MixTexture, TransformedTexture,
+};
+use crate::thin_film::ThinFilm as ThinFilmClass;
+use crate::vec3::{Float, Vec3};
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::io::prelude::*;
+use std::sync::Arc;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MapFile {
-    pub lookfrom: (f64, f64, f64),
-    pub lookat: (f64, f64, f64),
-    pub dist_to_focus: f64,
-    pub aperture: f64,
+    pub lookfrom: (Float, Float, Float),
+    pub lookat: (Float, Float, Float),
+    pub dist_to_focus: Float,
+    pub aperture: Float,
 
+    /// Material library files whose named materials are added to `materials`, paths being
+    /// relative to the map file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub libraries: Vec<String>,
+    /// Materials objects refer to by name, they take precedence over those of the libraries
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub materials: BTreeMap<String, Material>,
     pub objects: Vec<Object>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Object {
     Sphere {
-        position: (f64, f64, f64),
-        radius: f64,
+        position: (Float, Float, Float),
+        radius: Float,
         material: Material,
     },
     MovingSphere {
-        position: (f64, f64, f64),
-        shift: (f64, f64, f64),
-        radius: f64,
+        position: (Float, Float, Float),
+        shift: (Float, Float, Float),
+        radius: Float,
         material: Material,
     },
     RectSliceXy {
-        params: (f64, f64, f64, f64, f64),
+        params: (Float, Float, Float, Float, Float),
         material: Material,
     },
     RectSliceXz {
-        params: (f64, f64, f64, f64, f64),
+        params: (Float, Float, Float, Float, Float),
         material: Material,
     },
     RectSliceYz {
-        params: (f64, f64, f64, f64, f64),
+        params: (Float, Float, Float, Float, Float),
         material: Material,
     },
     FlipNormals(Box<Object>),
+    /// Any `object` with the parts where its `opacity` falls below `threshold` cut out, or made
+    /// partly transparent by their opacity when no threshold is given
+    Cutout {
+        object: Box<Object>,
+        opacity: Texture,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        threshold: Option<Float>,
+    },
     BoxObject {
-        p0: (f64, f64, f64),
-        p1: (f64, f64, f64),
+        p0: (Float, Float, Float),
+        p1: (Float, Float, Float),
         material: Material,
     },
     BvhNode {
         objects: Vec<Object>,
-        material: Material,
     },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Material {
-    Lambertian { texture: Texture },
-    Dielectric(f64),
-    Metal { texture: Texture, fuzz: f64 },
-    DiffuseLight { texture: Texture },
+    /// Material of the `materials` table with the given name, shared by everything using it. It
+    /// takes the normal and bump maps of its definition.
+    Named(String),
+    Lambertian {
+        texture: Texture,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    /// Rough diffuse surface, `sigma` being the slope deviation of its facets in degrees
+    OrenNayar {
+        texture: Texture,
+        sigma: Float,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    /// Dusty surface lit like the Moon
+    LommelSeeliger {
+        texture: Texture,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    Dielectric(Dielectric),
+    RoughDielectric {
+        ior: Float,
+        roughness: Float,
+        #[serde(flatten)]
+        medium: Medium,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    Metal {
+        texture: Texture,
+        fuzz: Float,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    Conductor {
+        ior: ComplexIor,
+        roughness: (Float, Float),
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        film: Option<ThinFilm>,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    /// Cloth with a `sheen` lobe catching the light at grazing angles over a diffuse base
+    Velvet {
+        texture: Texture,
+        sheen: Texture,
+        roughness: Float,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    DiffuseLight {
+        texture: Texture,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    Principled(Box<Principled>),
+    /// Blends `first` into `second` as `factor` goes from zero to one
+    Mix {
+        first: Box<Material>,
+        second: Box<Material>,
+        factor: Parameter,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    /// Translucent dielectric such as skin, wax or marble, inside which light scatters around
+    Subsurface {
+        ior: Float,
+        #[serde(default)]
+        roughness: Float,
+        #[serde(flatten)]
+        scattering: Scattering,
+        #[serde(default)]
+        anisotropy: Float,
+        #[serde(default)]
+        priority: u32,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+    /// Clear dielectric coat over the `base` material
+    Coated {
+        base: Box<Material>,
+        ior: Float,
+        #[serde(default)]
+        roughness: Float,
+        #[serde(flatten)]
+        maps: SurfaceMaps,
+    },
+}
+
+/// Parameters of the principled material, the ones left out take the defaults of Disney's BSDF
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Principled {
+    pub base_color: Texture,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roughness: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub specular: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub specular_tint: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sheen: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clearcoat: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clearcoat_gloss: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transmission: Option<Parameter>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ior: Option<Float>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emission: Option<Texture>,
+    #[serde(flatten)]
+    pub maps: SurfaceMaps,
+}
+
+/// Density of a subsurface medium, given either through its coefficients per unit of distance or
+/// through the color the object ends up with and the mean distance light travels inside of it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Scattering {
+    Coefficients {
+        scattering: (Float, Float, Float),
+        #[serde(default)]
+        absorption: (Float, Float, Float),
+    },
+    Radius {
+        albedo: (Float, Float, Float),
+        radius: (Float, Float, Float),
+    },
+}
+
+/// Optional normal and bump maps of a material. They perturb the shading normal of the whole
+/// material, so every layer of a `Mix` or `Coated` material given them shades the same detail.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SurfaceMaps {
+    /// Tangent space normal map
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<Texture>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bump_map: Option<BumpMap>,
+}
+
+/// Grayscale height map, the surface being displaced by its values times `scale` scene units
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BumpMap {
+    pub texture: Texture,
+    pub scale: Float,
+}
+
+/// Scalar material parameter, given either as a constant or read from a texture
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Parameter {
+    Value(Float),
+    Texture(Texture),
+}
+
+/// Dielectrics are either given by their index of refraction alone, or along with a thin film
+/// coating and the optional properties of the medium inside of them. Unknown fields are rejected,
+/// as a misspelled property would otherwise leave the medium clear without notice.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+#[allow(clippy::large_enum_variant)]
+pub enum Dielectric {
+    Ior(Float),
+    // NOTE: The properties of the medium and the surface maps are listed here instead of
+    // flattening `Medium` and `SurfaceMaps`, which serde does not support along with rejecting
+    // unknown fields
+    Medium {
+        ior: Float,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        film: Option<ThinFilm>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dispersion: Option<Dispersion>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        absorption: Option<(Float, Float, Float)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transmission_color: Option<(Float, Float, Float)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transmission_distance: Option<Float>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        priority: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        normal_map: Option<Texture>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bump_map: Option<BumpMap>,
+    },
+}
+
+/// Thin transparent film coating a surface, `thickness` being given in nanometers
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct ThinFilm {
+    pub thickness: Float,
+    pub ior: Float,
+}
+
+/// Optional properties of the medium inside a dielectric. Absorption is either given directly per
+/// unit of distance, or as the color left after light travels `transmission_distance`, which
+/// defaults to one. When a dispersion is given it replaces the index of refraction of the
+/// dielectric.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Medium {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispersion: Option<Dispersion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub absorption: Option<(Float, Float, Float)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transmission_color: Option<(Float, Float, Float)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transmission_distance: Option<Float>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u32>,
+}
+
+/// Variation of the index of refraction with wavelength, coefficients expect wavelengths in
+/// micrometers
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Dispersion {
+    Cauchy {
+        a: Float,
+        b: Float,
+    },
+    Sellmeier {
+        b: (Float, Float, Float),
+        c: (Float, Float, Float),
+    },
+    Bk7,
+    Diamond,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum ComplexIor {
+    Gold,
+    Copper,
+    Aluminium,
+    Silver,
+    Custom {
+        eta: (Float, Float, Float),
+        k: (Float, Float, Float),
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Texture {
+    /// Color given in eight bit channels, values above 255 make brighter than white colors
     SolidTexture(u16, u16, u16),
+    /// Color given as linear floats, which can go above one for lights
+    Linear(Float, Float, Float),
     CheckerTexture {
         odd: Box<Texture>,
         even: Box<Texture>,
     },
     NoiseTexture {
-        scale: f64,
+        scale: Float,
     },
     ImageTexture {
         path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        filter: Option<Filter>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        wrap: Option<Wrap>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        transform: Option<UvTransform>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        color_space: Option<ColorSpace>,
+    },
+    /// Fractal noise in shades of gray, ridged noise folds it into sharp crests
+    Fbm {
+        noise: Noise,
+        #[serde(default)]
+        ridged: bool,
+    },
+    Worley {
+        #[serde(default)]
+        seed: u32,
+        scale: Float,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        feature: Option<WorleyFeature>,
+    },
+    Wood {
+        noise: Noise,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        distortion: Option<Float>,
+        light: (Float, Float, Float),
+        dark: (Float, Float, Float),
+    },
+    Marble {
+        noise: Noise,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        turbulence: Option<Float>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        ramp: Vec<RampStop>,
+    },
+    Gradient {
+        axis: GradientAxis,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scale: Option<Float>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<Float>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        ramp: Vec<RampStop>,
+    },
+    /// Blends from the first texture to the second. This and the operators after it take other
+    /// textures as their inputs, so they nest into graphs.
+    Mix {
+        first: Box<Texture>,
+        second: Box<Texture>,
+        factor: Box<Parameter>,
+    },
+    Multiply {
+        first: Box<Texture>,
+        second: Box<Texture>,
+    },
+    Add {
+        first: Box<Texture>,
+        second: Box<Texture>,
+    },
+    /// Looks the average of the channels of a texture up in a color ramp
+    Ramp {
+        texture: Box<Texture>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        ramp: Vec<RampStop>,
+    },
+    /// Maps every channel linearly from one range to another, the range mapped from can not be
+    /// empty
+    Remap {
+        texture: Box<Texture>,
+        from: (Float, Float),
+        to: (Float, Float),
+    },
+    Invert {
+        texture: Box<Texture>,
+    },
+    /// Limits every channel to a range, zero to one when left out
+    Clamp {
+        texture: Box<Texture>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min: Option<Float>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max: Option<Float>,
+    },
+    /// Moves the texture coordinates and scales and offsets the points a texture is looked up at
+    Transform {
+        texture: Box<Texture>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uv: Option<UvTransform>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scale: Option<(Float, Float, Float)>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<(Float, Float, Float)>,
+    },
+    /// Spreads a single channel of a texture over all three
+    Channel {
+        texture: Box<Texture>,
+        channel: Channel,
     },
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Channel {
+    R,
+    G,
+    B,
+    Luminance,
+}
+
+/// Parameters of the fractal noise behind procedural textures, octaves left out default to six,
+/// each doubling the frequency and halving the amplitude
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Noise {
+    #[serde(default)]
+    pub seed: u32,
+    pub scale: Float,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub octaves: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lacunarity: Option<Float>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<Float>,
+}
+
+/// Stop of a color ramp, colors are linear. Ramps left empty go from black to white.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RampStop {
+    pub position: Float,
+    pub color: (Float, Float, Float),
+}
+
+/// Feature of the cells a Worley texture shows, the distance to their centers when left out
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum WorleyFeature {
+    Distance,
+    Edges,
+    Cells,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum GradientAxis {
+    U,
+    V,
+    X,
+    Y,
+    Z,
+}
+
+/// Color space an image is stored in, `Linear` is meant for data such as normal and roughness
+/// maps as well. Left out, Radiance and OpenEXR images are linear and all others sRGB.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum ColorSpace {
+    Srgb,
+    Linear,
+    AcesCg,
+}
+
+/// Filtering of image textures, nearest when left out
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Bicubic,
+}
+
+/// Behaviour of image textures outside of the unit square, clamped when left out
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum Wrap {
+    Repeat,
+    Mirror,
+    Clamp,
+}
+
+/// Transform of the texture coordinates of an image, rotation is given in degrees
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct UvTransform {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scale: Option<(Float, Float)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<(Float, Float)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<Float>,
+}
+
 impl Object {
-    pub fn parse(self) -> Box<dyn Hitable> {
+    pub fn parse(self, library: &MaterialLibrary) -> Box<dyn Hitable> {
         match self {
             Object::Sphere {
                 position,
@@ -92,7 +549,7 @@ impl Object {
             } => Box::new(Sphere::with_values(
                 Vec3::from(position),
                 radius,
-                MapFile::build_material(material),
+                MapFile::build_material(material, library),
             )),
             Object::MovingSphere {
                 position,
@@ -107,26 +564,40 @@ impl Object {
                 0.0,
                 1.0,
                 radius,
-                MapFile::build_material(material),
+                MapFile::build_material(material, library),
+            )),
+            Object::RectSliceXy { params, material } => Box::new(RectSliceXy::new(
+                MapFile::build_material(material, library),
+                params,
+            )),
+            Object::RectSliceXz { params, material } => Box::new(RectSliceXz::new(
+                MapFile::build_material(material, library),
+                params,
+            )),
+            Object::RectSliceYz { params, material } => Box::new(RectSliceYz::new(
+                MapFile::build_material(material, library),
+                params,
+            )),
+            Object::FlipNormals(object) => Box::new(FlipNormals::new(object.parse(library))),
+            Object::Cutout {
+                object,
+                opacity,
+                threshold,
+            } => Box::new(Cutout::new(
+                object.parse(library),
+                MapFile::build_texture(opacity),
+                threshold,
             )),
-            Object::RectSliceXy { params, material } => {
-                Box::new(RectSliceXy::new(MapFile::build_material(material), params))
-            }
-            Object::RectSliceXz { params, material } => {
-                Box::new(RectSliceXz::new(MapFile::build_material(material), params))
-            }
-            Object::RectSliceYz { params, material } => {
-                Box::new(RectSliceYz::new(MapFile::build_material(material), params))
-            }
-            Object::FlipNormals(object) => Box::new(FlipNormals::new(object.parse())),
             Object::BoxObject { p0, p1, material } => Box::new(BoxObject::new(
                 Vec3::from(p0),
                 Vec3::from(p1),
-                MapFile::build_material(material),
+                MapFile::build_material(material, library),
             )),
-            Object::BvhNode { objects, material } => Box::new(BvhNode::new(
-                &mut objects.into_iter().map(Object::parse).collect::<_>(),
-                MapFile::build_material(material),
+            Object::BvhNode { objects } => Box::new(BvhNode::new(
+                objects
+                    .into_iter()
+                    .map(|object| object.parse(library))
+                    .collect::<_>(),
                 0.0,
                 1.0,
             )),
@@ -134,42 +605,527 @@ impl Object {
     }
 }
 
+/// Runtime materials of the named materials of a map. Each one is built the first time it is used
+/// and then shared by every object and material referring to it.
+pub struct MaterialLibrary {
+    definitions: BTreeMap<String, Material>,
+    built: RefCell<HashMap<String, Arc<dyn MaterialClass>>>,
+    building: RefCell<Vec<String>>,
+}
+
+impl MaterialLibrary {
+    pub fn new(definitions: BTreeMap<String, Material>) -> Self {
+        Self {
+            definitions,
+            built: RefCell::new(HashMap::new()),
+            building: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns the material called `name`, panics if there is none or if it refers to itself
+    pub fn get(&self, name: &str) -> Arc<dyn MaterialClass> {
+        if let Some(material) = self.built.borrow().get(name) {
+            return Arc::clone(material);
+        }
+
+        let definition = self
+            .definitions
+            .get(name)
+            .unwrap_or_else(|| panic!("Unknown material {}", name))
+            .clone();
+        if self
+            .building
+            .borrow()
+            .iter()
+            .any(|building| building == name)
+        {
+            panic!("Material {} refers to itself", name);
+        }
+
+        self.building.borrow_mut().push(name.to_owned());
+        let material: Arc<dyn MaterialClass> = Arc::from(MapFile::build_material(definition, self));
+        self.building.borrow_mut().pop();
+
+        self.built
+            .borrow_mut()
+            .insert(name.to_owned(), Arc::clone(&material));
+        material
+    }
+}
+
 impl MapFile {
     pub fn build_world(&self) -> HitableList {
-        let mut world = HitableList::new();
+        let mut materials = BTreeMap::new();
+        for path in self.libraries.iter() {
+            materials.extend(MapFile::load_library(path));
+        }
+        materials.extend(self.materials.clone());
+        let library = MaterialLibrary::new(materials);
 
+        let mut world = HitableList::new();
         for object in self.objects.iter().cloned() {
-            world.put(object.parse())
+            world.put(object.parse(&library))
         }
 
         world
     }
 
-    pub fn build_material(material: Material) -> Box<dyn MaterialClass> {
-        match material {
-            Material::Lambertian { texture } => {
-                Box::new(Lambertian::new(MapFile::build_texture(texture)))
+    pub fn build_material(material: Material, library: &MaterialLibrary) -> Box<dyn MaterialClass> {
+        let (material, maps): (Box<dyn MaterialClass>, _) = match material {
+            Material::Named(name) => (Box::new(library.get(&name)), SurfaceMaps::default()),
+            Material::Lambertian { texture, maps } => (
+                Box::new(Lambertian::new(MapFile::build_texture(texture))),
+                maps,
+            ),
+            Material::OrenNayar {
+                texture,
+                sigma,
+                maps,
+            } => (
+                Box::new(OrenNayar::new(MapFile::build_texture(texture), sigma)),
+                maps,
+            ),
+            Material::LommelSeeliger { texture, maps } => (
+                Box::new(LommelSeeliger::new(MapFile::build_texture(texture))),
+                maps,
+            ),
+            Material::Dielectric(Dielectric::Ior(ior)) => {
+                (Box::new(DielectricClass::new(ior)), SurfaceMaps::default())
+            }
+            Material::Dielectric(Dielectric::Medium {
+                ior,
+                film,
+                dispersion,
+                absorption,
+                transmission_color,
+                transmission_distance,
+                priority,
+                normal_map,
+                bump_map,
+            }) => {
+                let medium = Medium {
+                    dispersion,
+                    absorption,
+                    transmission_color,
+                    transmission_distance,
+                    priority,
+                };
+                let mut material = DielectricClass::with_medium(MapFile::build_medium(ior, medium));
+                if let Some(film) = film {
+                    material = material.thin_film(MapFile::build_film(film));
+                }
+                let maps = SurfaceMaps {
+                    normal_map,
+                    bump_map,
+                };
+                (Box::new(material), maps)
+            }
+            Material::RoughDielectric {
+                ior,
+                roughness,
+                medium,
+                maps,
+            } => (
+                Box::new(RoughDielectric::with_medium(
+                    MapFile::build_medium(ior, medium),
+                    roughness,
+                )),
+                maps,
+            ),
+            Material::Metal {
+                texture,
+                fuzz,
+                maps,
+            } => (
+                Box::new(Metal::new(MapFile::build_texture(texture), fuzz)),
+                maps,
+            ),
+            Material::Conductor {
+                ior,
+                roughness,
+                film,
+                maps,
+            } => {
+                let mut material =
+                    Conductor::new(MapFile::build_ior(ior), roughness.0, roughness.1);
+                if let Some(film) = film {
+                    material = material.thin_film(MapFile::build_film(film));
+                }
+                (Box::new(material), maps)
             }
-            Material::Dielectric(x) => Box::new(Dielectric::new(x)),
-            Material::Metal { texture, fuzz } => {
-                Box::new(Metal::new(MapFile::build_texture(texture), fuzz))
+            Material::Velvet {
+                texture,
+                sheen,
+                roughness,
+                maps,
+            } => (
+                Box::new(Velvet::new(
+                    MapFile::build_texture(texture),
+                    MapFile::build_texture(sheen),
+                    roughness,
+                )),
+                maps,
+            ),
+            Material::DiffuseLight { texture, maps } => (
+                Box::new(DiffuseLight::new(MapFile::build_texture(texture))),
+                maps,
+            ),
+            Material::Principled(mut principled) => {
+                let maps = std::mem::take(&mut principled.maps);
+                (Box::new(MapFile::build_principled(*principled)), maps)
             }
-            Material::DiffuseLight { texture } => {
-                Box::new(DiffuseLight::new(MapFile::build_texture(texture)))
+            Material::Mix {
+                first,
+                second,
+                factor,
+                maps,
+            } => (
+                Box::new(Mix::new(
+                    MapFile::build_material(*first, library),
+                    MapFile::build_material(*second, library),
+                    MapFile::build_parameter(factor),
+                )),
+                maps,
+            ),
+            Material::Subsurface {
+                ior,
+                roughness,
+                scattering,
+                anisotropy,
+                priority,
+                maps,
+            } => {
+                let mut medium = match scattering {
+                    Scattering::Coefficients {
+                        scattering,
+                        absorption,
+                    } => MediumClass {
+                        scattering: scattering.into(),
+                        absorption: absorption.into(),
+                        ..MediumClass::new(ior)
+                    },
+                    Scattering::Radius { albedo, radius } => {
+                        MediumClass::subsurface(ior, albedo.into(), radius.into())
+                    }
+                };
+                medium.anisotropy = anisotropy;
+                medium.priority = priority;
+                (
+                    Box::new(RoughDielectric::with_medium(medium, roughness)),
+                    maps,
+                )
             }
+            Material::Coated {
+                base,
+                ior,
+                roughness,
+                maps,
+            } => (
+                Box::new(Coated::new(
+                    MapFile::build_material(*base, library),
+                    ior,
+                    roughness,
+                )),
+                maps,
+            ),
+        };
+        MapFile::build_maps(material, maps)
+    }
+
+    /// Wraps `material` so that its normal and bump maps, if any, perturb all of its layers
+    pub fn build_maps(
+        material: Box<dyn MaterialClass>,
+        maps: SurfaceMaps,
+    ) -> Box<dyn MaterialClass> {
+        if maps.normal_map.is_none() && maps.bump_map.is_none() {
+            return material;
+        }
+
+        let mut material = Bumped::new(material);
+        if let Some(normal_map) = maps.normal_map {
+            material = material.normal_map(MapFile::build_texture(normal_map));
+        }
+        if let Some(bump_map) = maps.bump_map {
+            material = material.bump_map(MapFile::build_texture(bump_map.texture), bump_map.scale);
+        }
+        Box::new(material)
+    }
+
+    pub fn build_principled(principled: Principled) -> PrincipledClass {
+        let mut material = PrincipledClass::new(MapFile::build_texture(principled.base_color));
+        if let Some(metallic) = principled.metallic {
+            material = material.metallic(MapFile::build_parameter(metallic));
+        }
+        if let Some(roughness) = principled.roughness {
+            material = material.roughness(MapFile::build_parameter(roughness));
+        }
+        if let Some(specular) = principled.specular {
+            material = material.specular(MapFile::build_parameter(specular));
+        }
+        if let Some(specular_tint) = principled.specular_tint {
+            material = material.specular_tint(MapFile::build_parameter(specular_tint));
+        }
+        if let Some(sheen) = principled.sheen {
+            material = material.sheen(MapFile::build_parameter(sheen));
+        }
+        if let Some(clearcoat) = principled.clearcoat {
+            material = material.clearcoat(MapFile::build_parameter(clearcoat));
+        }
+        if let Some(clearcoat_gloss) = principled.clearcoat_gloss {
+            material = material.clearcoat_gloss(MapFile::build_parameter(clearcoat_gloss));
+        }
+        if let Some(transmission) = principled.transmission {
+            material = material.transmission(
+                MapFile::build_parameter(transmission),
+                principled.ior.unwrap_or(1.5),
+            );
+        }
+        if let Some(emission) = principled.emission {
+            material = material.emission(MapFile::build_texture(emission));
+        }
+        material
+    }
+
+    pub fn build_parameter(parameter: Parameter) -> Box<dyn TextureClass> {
+        match parameter {
+            Parameter::Value(value) => SolidTexture::new((value, value, value).into()),
+            Parameter::Texture(texture) => MapFile::build_texture(texture),
+        }
+    }
+
+    pub fn build_medium(ior: Float, medium: Medium) -> MediumClass {
+        let mut result = match medium.dispersion {
+            Some(dispersion) => MediumClass::dispersive(MapFile::build_dispersion(dispersion)),
+            None => MediumClass::new(ior),
+        };
+        if let Some(absorption) = medium.absorption {
+            result.absorption = absorption.into();
+        } else if let Some(color) = medium.transmission_color {
+            result.absorption = MediumClass::absorption_from_color(
+                color.into(),
+                medium.transmission_distance.unwrap_or(1.0),
+            );
+        }
+        result.priority = medium.priority.unwrap_or(0);
+        result
+    }
+
+    pub fn build_dispersion(dispersion: Dispersion) -> DispersionClass {
+        match dispersion {
+            Dispersion::Cauchy { a, b } => DispersionClass::Cauchy { a, b },
+            Dispersion::Sellmeier { b, c } => DispersionClass::Sellmeier {
+                b: [b.0, b.1, b.2],
+                c: [c.0, c.1, c.2],
+            },
+            Dispersion::Bk7 => DispersionClass::bk7(),
+            Dispersion::Diamond => DispersionClass::diamond(),
+        }
+    }
+
+    pub fn build_film(film: ThinFilm) -> ThinFilmClass {
+        ThinFilmClass::new(film.thickness, film.ior)
+    }
+
+    pub fn build_ior(ior: ComplexIor) -> ComplexIorClass {
+        match ior {
+            ComplexIor::Gold => ComplexIorClass::gold(),
+            ComplexIor::Copper => ComplexIorClass::copper(),
+            ComplexIor::Aluminium => ComplexIorClass::aluminium(),
+            ComplexIor::Silver => ComplexIorClass::silver(),
+            ComplexIor::Custom { eta, k } => ComplexIorClass::new(eta.into(), k.into()),
         }
     }
 
+    pub fn build_uv_transform(transform: UvTransform) -> UvTransformClass {
+        UvTransformClass::new(
+            transform.scale.unwrap_or((1.0, 1.0)),
+            transform.offset.unwrap_or((0.0, 0.0)),
+            transform.rotation.unwrap_or(0.0),
+        )
+    }
+
+    pub fn build_fractal(noise: Noise) -> Fractal {
+        Fractal::new(
+            noise.seed,
+            noise.octaves.unwrap_or(6),
+            noise.lacunarity.unwrap_or(2.0),
+            noise.gain.unwrap_or(0.5),
+        )
+    }
+
+    pub fn build_ramp(ramp: Vec<RampStop>) -> ColorRamp {
+        if ramp.is_empty() {
+            return ColorRamp::grayscale();
+        }
+        ColorRamp::new(
+            ramp.into_iter()
+                .map(|stop| (stop.position, stop.color.into()))
+                .collect(),
+        )
+    }
+
     pub fn build_texture(texture: Texture) -> Box<dyn TextureClass> {
         match texture {
-            Texture::SolidTexture(r, g, b) => {
-                SolidTexture::new((r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0).into())
-            }
+            Texture::SolidTexture(r, g, b) => SolidTexture::new(
+                (r as Float / 255.0, g as Float / 255.0, b as Float / 255.0).into(),
+            ),
+            Texture::Linear(r, g, b) => SolidTexture::new((r, g, b).into()),
             Texture::CheckerTexture { odd, even } => {
                 CheckerTexture::new(MapFile::build_texture(*odd), MapFile::build_texture(*even))
             }
             Texture::NoiseTexture { scale } => NoiseTexture::new(scale),
-            Texture::ImageTexture { path } => ImageTexture::new(path.as_str()),
+            Texture::Fbm { noise, ridged } => {
+                let scale = noise.scale;
+                FbmTexture::new(MapFile::build_fractal(noise), scale, ridged)
+            }
+            Texture::Worley {
+                seed,
+                scale,
+                feature,
+            } => {
+                let feature = match feature.unwrap_or(WorleyFeature::Distance) {
+                    WorleyFeature::Distance => WorleyFeatureClass::Distance,
+                    WorleyFeature::Edges => WorleyFeatureClass::Edges,
+                    WorleyFeature::Cells => WorleyFeatureClass::Cells,
+                };
+                WorleyTexture::new(seed, scale, feature)
+            }
+            Texture::Wood {
+                noise,
+                distortion,
+                light,
+                dark,
+            } => {
+                let scale = noise.scale;
+                WoodTexture::new(
+                    MapFile::build_fractal(noise),
+                    scale,
+                    distortion.unwrap_or(0.5),
+                    light.into(),
+                    dark.into(),
+                )
+            }
+            Texture::Marble {
+                noise,
+                turbulence,
+                ramp,
+            } => {
+                let scale = noise.scale;
+                MarbleTexture::new(
+                    MapFile::build_fractal(noise),
+                    scale,
+                    turbulence.unwrap_or(5.0),
+                    MapFile::build_ramp(ramp),
+                )
+            }
+            Texture::Gradient {
+                axis,
+                scale,
+                offset,
+                ramp,
+            } => {
+                let axis = match axis {
+                    GradientAxis::U => GradientAxisClass::U,
+                    GradientAxis::V => GradientAxisClass::V,
+                    GradientAxis::X => GradientAxisClass::X,
+                    GradientAxis::Y => GradientAxisClass::Y,
+                    GradientAxis::Z => GradientAxisClass::Z,
+                };
+                GradientTexture::new(
+                    axis,
+                    scale.unwrap_or(1.0),
+                    offset.unwrap_or(0.0),
+                    MapFile::build_ramp(ramp),
+                )
+            }
+            Texture::Mix {
+                first,
+                second,
+                factor,
+            } => MixTexture::new(
+                MapFile::build_texture(*first),
+                MapFile::build_texture(*second),
+                MapFile::build_parameter(*factor),
+            ),
+            Texture::Multiply { first, second } => ArithmeticTexture::new(
+                Arithmetic::Multiply,
+                MapFile::build_texture(*first),
+                MapFile::build_texture(*second),
+            ),
+            Texture::Add { first, second } => ArithmeticTexture::new(
+                Arithmetic::Add,
+                MapFile::build_texture(*first),
+                MapFile::build_texture(*second),
+            ),
+            Texture::Ramp { texture, ramp } => AdjustedTexture::new(
+                MapFile::build_texture(*texture),
+                Adjustment::Ramp(MapFile::build_ramp(ramp)),
+            ),
+            Texture::Remap { texture, from, to } => {
+                if from.0 == from.1 {
+                    panic!(
+                        "Remap needs two different bounds to map from, got {:?}",
+                        from
+                    );
+                }
+                AdjustedTexture::new(
+                    MapFile::build_texture(*texture),
+                    Adjustment::Remap(from, to),
+                )
+            }
+            Texture::Invert { texture } => {
+                AdjustedTexture::new(MapFile::build_texture(*texture), Adjustment::Invert)
+            }
+            Texture::Clamp { texture, min, max } => AdjustedTexture::new(
+                MapFile::build_texture(*texture),
+                Adjustment::Clamp(min.unwrap_or(0.0), max.unwrap_or(1.0)),
+            ),
+            Texture::Transform {
+                texture,
+                uv,
+                scale,
+                offset,
+            } => TransformedTexture::new(
+                MapFile::build_texture(*texture),
+                uv.map_or_else(UvTransformClass::identity, MapFile::build_uv_transform),
+                scale.unwrap_or((1.0, 1.0, 1.0)).into(),
+                offset.unwrap_or((0.0, 0.0, 0.0)).into(),
+            ),
+            Texture::Channel { texture, channel } => {
+                let channel = match channel {
+                    Channel::R => ChannelClass::R,
+                    Channel::G => ChannelClass::G,
+                    Channel::B => ChannelClass::B,
+                    Channel::Luminance => ChannelClass::Luminance,
+                };
+                ChannelTexture::new(MapFile::build_texture(*texture), channel)
+            }
+            Texture::ImageTexture {
+                path,
+                filter,
+                wrap,
+                transform,
+                color_space,
+            } => {
+                let filter = match filter.unwrap_or(Filter::Nearest) {
+                    Filter::Nearest => FilterClass::Nearest,
+                    Filter::Bilinear => FilterClass::Bilinear,
+                    Filter::Bicubic => FilterClass::Bicubic,
+                };
+                let wrap = match wrap.unwrap_or(Wrap::Clamp) {
+                    Wrap::Repeat => WrapClass::Repeat,
+                    Wrap::Mirror => WrapClass::Mirror,
+                    Wrap::Clamp => WrapClass::Clamp,
+                };
+                let transform =
+                    transform.map_or_else(UvTransformClass::identity, MapFile::build_uv_transform);
+                let color_space = color_space.map(|color_space| match color_space {
+                    ColorSpace::Srgb => ColorSpaceClass::Srgb,
+                    ColorSpace::Linear => ColorSpaceClass::Linear,
+                    ColorSpace::AcesCg => ColorSpaceClass::AcesCg,
+                });
+                ImageTexture::with_options(path.as_str(), filter, wrap, transform, color_space)
+            }
         }
     }
 
@@ -179,11 +1135,11 @@ impl MapFile {
 
         for a in -8..8 {
             for b in -8..8 {
-                let pick = rng.gen::<f64>();
+                let pick = rng.gen::<Float>();
                 let center = (
-                    a as f64 + 0.9 * rng.gen::<f64>(),
-                    0.2f64,
-                    b as f64 + 0.9 * rng.gen::<f64>(),
+                    a as Float + 0.9 * rng.gen::<Float>(),
+                    0.2,
+                    b as Float + 0.9 * rng.gen::<Float>(),
                 );
                 if (Vec3::from(center) as Vec3 - Vec3::with_values(4.0, 0.2, 0.0)).len() > 0.9 {
                     if pick < 0.2 {
@@ -192,12 +1148,13 @@ impl MapFile {
                             radius: 0.2,
                             material: Material::Lambertian {
                                 texture: Texture::NoiseTexture { scale: 1.0 },
+                                maps: SurfaceMaps::default(),
                             },
                         });
                     } else if pick < 0.4 {
                         objects.push(Object::MovingSphere {
                             position: center,
-                            shift: (0.0, 0.0 * rng.gen::<f64>(), 0.0),
+                            shift: (0.0, 0.0 * rng.gen::<Float>(), 0.0),
                             radius: 0.2,
                             material: Material::Lambertian {
                                 texture: Texture::SolidTexture(
@@ -205,6 +1162,7 @@ impl MapFile {
                                     rng.gen::<u8>() as u16,
                                     rng.gen::<u8>() as u16,
                                 ),
+                                maps: SurfaceMaps::default(),
                             },
                         });
                     } else if pick < 0.7 {
@@ -218,13 +1176,14 @@ impl MapFile {
                                     rng.gen::<u8>() as u16,
                                 ),
                                 fuzz: 0.3,
+                                maps: SurfaceMaps::default(),
                             },
                         });
                     } else {
                         objects.push(Object::Sphere {
                             position: center,
                             radius: 0.2,
-                            material: Material::Dielectric(1.5),
+                            material: Material::Dielectric(Dielectric::Ior(1.5)),
                         });
                     }
                 }
@@ -239,6 +1198,7 @@ impl MapFile {
                     odd: Box::new(Texture::SolidTexture(235, 47, 6)),
                     even: Box::new(Texture::SolidTexture(12, 36, 97)),
                 },
+                maps: SurfaceMaps::default(),
             },
         });
 
@@ -247,6 +1207,7 @@ impl MapFile {
             radius: 80.0,
             material: Material::Lambertian {
                 texture: Texture::NoiseTexture { scale: 1.0 },
+                maps: SurfaceMaps::default(),
             },
         });
         /*
@@ -262,7 +1223,7 @@ impl MapFile {
         objects.push(Object::Sphere {
             position: (0.0, 1.0, 0.0),
             radius: 1.0,
-            material: Material::Dielectric(1.5),
+            material: Material::Dielectric(Dielectric::Ior(1.5)),
         });
 
         /*
@@ -281,6 +1242,10 @@ impl MapFile {
             material: Material::Lambertian {
                 texture: Texture::ImageTexture {
                     path: "./textures/earthmap.jpg".into(),
+                    filter: None,
+                    wrap: None,
+                    transform: None,
+                    color_space: None,
                 },
             },
         });
@@ -291,48 +1256,54 @@ impl MapFile {
             lookat: (0.0, 0.0, 0.0),
             dist_to_focus: 10.0,
             aperture: 0.0,
+            libraries: Vec::new(),
+            materials: BTreeMap::new(),
             objects,
         }
     }
 
     pub fn test_map() -> MapFile {
-        let mut objects = Vec::new();
-        objects.push(Object::Sphere {
-            position: (0.0, -1000.0, 0.0),
-            radius: 1000.0,
-            material: Material::Lambertian {
-                texture: Texture::NoiseTexture { scale: 1.0 },
+        let objects = vec![
+            Object::Sphere {
+                position: (0.0, -1000.0, 0.0),
+                radius: 1000.0,
+                material: Material::Lambertian {
+                    texture: Texture::NoiseTexture { scale: 1.0 },
+                    maps: SurfaceMaps::default(),
+                },
             },
-        });
-
-        objects.push(Object::Sphere {
-            position: (0.0, -2.0, 0.0),
-            radius: 2.0,
-            material: Material::Lambertian {
-                texture: Texture::NoiseTexture { scale: 1.0 },
+            Object::Sphere {
+                position: (0.0, -2.0, 0.0),
+                radius: 2.0,
+                material: Material::Lambertian {
+                    texture: Texture::NoiseTexture { scale: 1.0 },
+                    maps: SurfaceMaps::default(),
+                },
             },
-        });
-
-        objects.push(Object::Sphere {
-            position: (0.0, 7.0, 0.0),
-            radius: 2.0,
-            material: Material::DiffuseLight {
-                texture: Texture::SolidTexture(1020, 1020, 1020),
+            Object::Sphere {
+                position: (0.0, 7.0, 0.0),
+                radius: 2.0,
+                material: Material::DiffuseLight {
+                    texture: Texture::Linear(4.0, 4.0, 4.0),
+                    maps: SurfaceMaps::default(),
+                },
             },
-        });
-
-        objects.push(Object::RectSliceXy {
-            params: (0.0, 3.0, 0.0, 3.0, -2.0),
-            material: Material::DiffuseLight {
-                texture: Texture::SolidTexture(1020, 1020, 1020),
+            Object::RectSliceXy {
+                params: (0.0, 3.0, 0.0, 3.0, -2.0),
+                material: Material::DiffuseLight {
+                    texture: Texture::Linear(4.0, 4.0, 4.0),
+                    maps: SurfaceMaps::default(),
+                },
             },
-        });
+        ];
 
         Self {
             lookfrom: (40.0, 7.0, 0.0),
             lookat: (0.0, 0.0, 0.0),
             dist_to_focus: 10.0,
             aperture: 0.0,
+            libraries: Vec::new(),
+            materials: BTreeMap::new(),
             objects,
         }
     }
@@ -341,17 +1312,21 @@ impl MapFile {
         let mut objects = Vec::new();
         let red_material = Material::Lambertian {
             texture: Texture::SolidTexture(255, 0, 0),
+            maps: SurfaceMaps::default(),
         };
         let white_material = Material::Lambertian {
             texture: Texture::SolidTexture(255, 255, 255),
+            maps: SurfaceMaps::default(),
         };
 
         let green_material = Material::Lambertian {
             texture: Texture::SolidTexture(0, 255, 0),
+            maps: SurfaceMaps::default(),
         };
 
         let light_material = Material::DiffuseLight {
-            texture: Texture::SolidTexture(255 * 15, 255 * 15, 255 * 15),
+            texture: Texture::Linear(15.0, 15.0, 15.0),
+            maps: SurfaceMaps::default(),
         };
 
         objects.push(Object::FlipNormals(Box::new(Object::RectSliceYz {
@@ -401,6 +1376,8 @@ impl MapFile {
             lookat: (278.0, 278.0, 0.0),
             dist_to_focus: 10.0,
             aperture: 0.0,
+            libraries: Vec::new(),
+            materials: BTreeMap::new(),
             objects,
         }
     }
@@ -408,20 +1385,26 @@ impl MapFile {
     pub fn map2() -> MapFile {
         let mut ground_box = Vec::new();
         let mut objects = Vec::new();
-        let ground = Material::Lambertian {
-            texture: Texture::SolidTexture(122, 212, 135),
-        };
+        let mut materials = BTreeMap::new();
+        materials.insert(
+            "ground".to_owned(),
+            Material::Lambertian {
+                texture: Texture::SolidTexture(122, 212, 135),
+                maps: SurfaceMaps::default(),
+            },
+        );
+        let ground = Material::Named("ground".to_owned());
 
         let mut rng = rand::thread_rng();
 
         for i in 0..20 {
             for j in 0..20 {
                 let w = 100.0;
-                let x0 = 500.0 - (i as f64) * w;
-                let z0 = 1500.0 - (j as f64) * w;
+                let x0 = 500.0 - (i as Float) * w;
+                let z0 = 1500.0 - (j as Float) * w;
                 let y0 = 0.0;
                 let x1 = x0 + w;
-                let y1 = 100.0 * (rng.gen::<f64>() + 0.01);
+                let y1 = 100.0 * (rng.gen::<Float>() + 0.01);
                 let z1 = z0 + w;
 
                 ground_box.push(Object::BoxObject {
@@ -435,20 +1418,20 @@ impl MapFile {
         ground_box.push(Object::BoxObject {
             p0: (165.0, 0.0, -20.0),
             p1: (300.0, 105.0, 150.0),
-            material: ground.clone(),
+            material: ground,
         });
 
         objects.push(Object::BvhNode {
             objects: ground_box,
-            material: ground,
         });
 
-        let l = 255 * 7;
+        let l = 7.0;
 
         objects.push(Object::RectSliceXz {
             params: (123.0, 423.0, 147.0, 412.0, 554.0),
             material: Material::DiffuseLight {
-                texture: Texture::SolidTexture(l, l, l),
+                texture: Texture::Linear(l, l, l),
+                maps: SurfaceMaps::default(),
             },
         });
 
@@ -458,21 +1441,24 @@ impl MapFile {
             radius: 50.0,
             material: Material::Lambertian {
                 texture: Texture::SolidTexture(178, 76, 25),
+                maps: SurfaceMaps::default(),
             },
         });
 
         objects.push(Object::Sphere {
             position: (250.0, 150.0, 45.0),
             radius: 50.0,
-            material: Material::Dielectric(1.5),
+            material: Material::Dielectric(Dielectric::Ior(1.5)),
         });
 
         objects.push(Object::Sphere {
             position: (0.0, 150.0, 145.0),
             radius: 50.0,
-            material: Material::Metal {
-                texture: Texture::SolidTexture(204, 204, 230),
-                fuzz: 10.0,
+            material: Material::Conductor {
+                ior: ComplexIor::Aluminium,
+                roughness: (0.6, 0.6),
+                film: None,
+                maps: SurfaceMaps::default(),
             },
         });
 
@@ -482,7 +1468,12 @@ impl MapFile {
             material: Material::Lambertian {
                 texture: Texture::ImageTexture {
                     path: "./textures/earthmap.jpg".into(),
+                    filter: None,
+                    wrap: None,
+                    transform: None,
+                    color_space: None,
                 },
+                maps: SurfaceMaps::default(),
             },
         });
 
@@ -492,7 +1483,12 @@ impl MapFile {
             material: Material::Lambertian {
                 texture: Texture::ImageTexture {
                     path: "./textures/stone.jpg".into(),
+                    filter: None,
+                    wrap: None,
+                    transform: None,
+                    color_space: None,
                 },
+                maps: SurfaceMaps::default(),
             },
         });
 
@@ -501,6 +1497,8 @@ impl MapFile {
             lookfrom: (478.0, 278.0, -600.0),
             dist_to_focus: 10.0,
             aperture: 0.0,
+            libraries: Vec::new(),
+            materials,
             objects,
         }
     }
@@ -512,6 +1510,27 @@ impl MapFile {
     }
 
     pub fn load_from_file<T: AsRef<std::path::Path>>(file: T) -> Self {
+        let directory = file
+            .as_ref()
+            .parent()
+            .map(std::path::Path::to_path_buf)
+            .unwrap_or_default();
+        let mut file = std::fs::File::open(file).unwrap();
+        let mut buf = String::new();
+
+        file.read_to_string(&mut buf).unwrap();
+
+        let mut map: Self = serde_json::from_str(buf.as_ref()).unwrap();
+        // NOTE: Library paths are made relative to the working directory, so that the map still
+        // finds them once dumped elsewhere
+        for path in map.libraries.iter_mut() {
+            *path = directory.join(&path).to_string_lossy().into_owned();
+        }
+        map
+    }
+
+    /// Loads a material library file, a JSON object of materials by name
+    pub fn load_library<T: AsRef<std::path::Path>>(file: T) -> BTreeMap<String, Material> {
         let mut file = std::fs::File::open(file).unwrap();
         let mut buf = String::new();
 
@@ -520,3 +1539,146 @@ impl MapFile {
         serde_json::from_str(buf.as_ref()).unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(value: u16) -> Material {
+        Material::Lambertian {
+            texture: Texture::SolidTexture(value, value, value),
+            maps: SurfaceMaps::default(),
+        }
+    }
+
+    #[test]
+    fn named_materials_are_built_once_and_shared() {
+        let mut definitions = BTreeMap::new();
+        definitions.insert("paint".to_owned(), gray(100));
+        definitions.insert(
+            "worn paint".to_owned(),
+            Material::Mix {
+                first: Box::new(Material::Named("paint".to_owned())),
+                second: Box::new(gray(200)),
+                factor: Parameter::Value(0.5),
+                maps: SurfaceMaps::default(),
+            },
+        );
+        let library = MaterialLibrary::new(definitions);
+
+        let paint = library.get("paint");
+        assert!(Arc::ptr_eq(&paint, &library.get("paint")));
+        library.get("worn paint");
+        assert!(Arc::ptr_eq(&paint, &library.get("paint")));
+    }
+
+    #[test]
+    #[should_panic(expected = "refers to itself")]
+    fn materials_referring_to_themselves_are_rejected() {
+        let mut definitions = BTreeMap::new();
+        definitions.insert(
+            "loop".to_owned(),
+            Material::Coated {
+                base: Box::new(Material::Named("loop".to_owned())),
+                ior: 1.5,
+                roughness: 0.0,
+                maps: SurfaceMaps::default(),
+            },
+        );
+        MaterialLibrary::new(definitions).get("loop");
+    }
+
+    #[test]
+    fn libraries_are_loaded_next_to_the_map() {
+        let directory = std::env::temp_dir().join(format!("raytracer-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut library = BTreeMap::new();
+        library.insert("chalk".to_owned(), gray(230));
+        std::fs::write(
+            directory.join("library.json"),
+            serde_json::to_string(&library).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            directory.join("map.json"),
+            r#"{"lookfrom":[0,0,-1],"lookat":[0,0,0],"dist_to_focus":1,"aperture":0,
+                "libraries":["library.json"],
+                "objects":[{"Sphere":{"position":[0,0,0],"radius":1,"material":{"Named":"chalk"}}}]}"#,
+        )
+        .unwrap();
+
+        let map = MapFile::load_from_file(directory.join("map.json"));
+        let materials = MapFile::load_library(&map.libraries[0]);
+        assert!(materials.contains_key("chalk"));
+        map.build_world();
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "two different bounds")]
+    fn remapping_from_an_empty_range_is_rejected() {
+        let texture: Texture = serde_json::from_str(
+            r#"{"Remap":{"texture":{"SolidTexture":[0,0,0]},"from":[0.5,0.5],"to":[0,1]}}"#,
+        )
+        .unwrap();
+        MapFile::build_texture(texture);
+    }
+
+    #[test]
+    fn misspelled_medium_properties_are_rejected() {
+        let parse = |json| serde_json::from_str::<Material>(json);
+        assert!(parse(r#"{"Dielectric":{"ior":1.5,"absorption":[1,0,0],"priority":2}}"#).is_ok());
+        assert!(parse(r#"{"Dielectric":{"ior":1.5,"absorbtion":[1,0,0]}}"#).is_err());
+    }
+
+    #[test]
+    fn surface_maps_wrap_whole_materials() {
+        let bump_map = r#""bump_map":{"texture":{"NoiseTexture":{"scale":4}},"scale":0.1}"#;
+        let materials = [
+            format!(
+                r#"{{"Mix":{{"first":{{"Lambertian":{{"texture":{{"SolidTexture":[200,0,0]}}}}}},
+                    "second":{{"Dielectric":1.5}},"factor":0.5,{}}}}}"#,
+                bump_map
+            ),
+            format!(r#"{{"Dielectric":{{"ior":1.5,{}}}}}"#, bump_map),
+            format!(
+                r#"{{"Principled":{{"base_color":{{"SolidTexture":[200,0,0]}},{}}}}}"#,
+                bump_map
+            ),
+        ];
+        for json in materials.iter() {
+            let material: Material = serde_json::from_str(json).unwrap();
+            let material: Material =
+                serde_json::from_str(&serde_json::to_string(&material).unwrap()).unwrap();
+            let built = MapFile::build_material(material, &MaterialLibrary::new(BTreeMap::new()));
+            assert!(format!("{:?}", built).starts_with("Bumped"), "{}", json);
+        }
+
+        let plain = MapFile::build_material(gray(100), &MaterialLibrary::new(BTreeMap::new()));
+        assert!(!format!("{:?}", plain).starts_with("Bumped"));
+    }
+
+    #[test]
+    fn linear_colors_are_kept_above_one() {
+        let texture: Texture = serde_json::from_str(r#"{"Linear":[15,0.5,0]}"#).unwrap();
+        let color = MapFile::build_texture(texture).value(0.0, 0.0, Vec3::new());
+        assert_eq!(color, Vec3::with_values(15.0, 0.5, 0.0));
+    }
+
+    #[test]
+    fn texture_operators_nest() {
+        let texture: Texture = serde_json::from_str(
+            r#"{"Mix":{
+                "first":{"SolidTexture":[255,0,0]},
+                "second":{"Invert":{"texture":{"SolidTexture":[255,255,0]}}},
+                "factor":{"Channel":{"channel":"G","texture":
+                    {"Transform":{"texture":{"SolidTexture":[0,51,0]},"scale":[2,2,2]}}}}
+            }}"#,
+        )
+        .unwrap();
+        let color = MapFile::build_texture(texture).value(0.0, 0.0, Vec3::new());
+        assert!((color - Vec3::with_values(0.8, 0.0, 0.2)).len() < 1e-2);
+    }
+}
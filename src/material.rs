@@ -1,16 +1,20 @@
 use crate::hitable::HitRecord;
+use crate::medium::Medium;
+use crate::microfacet::{roughness_to_alpha, smith_g1, Gtr1, TrowbridgeReitz};
 use crate::ray::Ray;
-use crate::texture::Texture;
-use crate::vec3::Vec3;
+use crate::texture::{SolidTexture, Texture};
+use crate::thin_film::{Complex, ThinFilm};
+use crate::vec3::{Float, Onb, Vec3, PI};
 use dyn_clone::DynClone;
 use rand::prelude::*;
 use std::fmt::Debug as DebugTrait;
+use std::sync::Arc;
 
 pub fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - 2.0 * v.dot(n) * n
 }
 
-pub fn refract(v: Vec3, n: Vec3, ni_over_nt: f64, refracted: &mut Vec3) -> bool {
+pub fn refract(v: Vec3, n: Vec3, ni_over_nt: Float, refracted: &mut Vec3) -> bool {
     let uv = v.unit_vector();
     let dt = uv.dot(n);
     let discriminant = 1.0 - ni_over_nt * ni_over_nt * (1.0 - dt * dt);
@@ -21,27 +25,218 @@ pub fn refract(v: Vec3, n: Vec3, ni_over_nt: f64, refracted: &mut Vec3) -> bool
     false
 }
 
-pub fn schlick(cos: f64, ref_index: f64) -> f64 {
-    let mut r0 = (1.0 - ref_index) / (1.0 + ref_index);
-    r0 = r0 * r0;
-    r0 + (1.0 - r0) * (1.0 - cos).powi(5)
+/// Returns the index of refraction on the far side of a surface filled with `medium` relative to
+/// the side the ray arrived from
+fn relative_ior(medium: &Medium, hit_record: &HitRecord) -> Float {
+    let ior = medium.ior_at(hit_record.wavelength);
+    if hit_record.front_face {
+        ior / hit_record.surrounding_ior
+    } else {
+        hit_record.surrounding_ior / ior
+    }
+}
+
+/// Returns the Fresnel reflectance of a dielectric interface for light arriving at `cos` to the
+/// normal, `eta` being the index of refraction on the far side of the interface relative to the
+/// near one. Light that can not leave through the interface is reflected completely.
+pub fn fresnel_dielectric(cos: Float, eta: Float) -> Float {
+    let cos_i = cos.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+/// Samples reflection or refraction through a smooth dielectric interface, picking between them
+/// by the Fresnel reflectance. Transmitted radiance is scaled by `1 / eta^2`, as it is compressed
+/// into a smaller solid angle when entering a denser medium.
+fn sample_smooth_dielectric(
+    wo: Vec3,
+    eta: Float,
+    hit_record: &HitRecord,
+    rng: &mut dyn RngCore,
+) -> BsdfSample {
+    let cos = wo.dot(hit_record.normal);
+    let reflectance = fresnel_dielectric(cos, eta);
+    let mut refracted = Vec3::new();
+    if rng.gen::<Float>() < reflectance
+        || !refract(-wo, hit_record.normal, 1.0 / eta, &mut refracted)
+    {
+        return BsdfSample {
+            wi: reflect(-wo, hit_record.normal),
+            f: Vec3::with_values(1.0, 1.0, 1.0) * reflectance / cos,
+            pdf: reflectance,
+            specular: true,
+        };
+    }
+
+    let wi = refracted.unit_vector();
+    let transmittance = (1.0 - reflectance) / (eta * eta);
+    BsdfSample {
+        wi,
+        f: Vec3::with_values(1.0, 1.0, 1.0) * transmittance / wi.dot(hit_record.normal).abs(),
+        pdf: 1.0 - reflectance,
+        specular: true,
+    }
+}
+
+/// Returns the Fresnel reflectance of a conductor with the complex index of refraction
+/// `eta + i k` for light arriving at `cos` to the normal, averaged over both polarizations
+pub fn fresnel_conductor(cos: Float, eta: Float, k: Float) -> Float {
+    let cos = cos.clamp(0.0, 1.0);
+    let cos2 = cos * cos;
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+
+    0.5 * (rp + rs)
+}
+
+/// Returns a texture with the same value everywhere, used for the defaults of textured parameters
+fn gray_texture(value: Float) -> Box<dyn Texture> {
+    SolidTexture::new(Vec3::with_values(value, value, value))
+}
+
+/// Struct holds the result of sampling a material for an outgoing direction
+#[derive(Clone, Copy, Debug)]
+pub struct BsdfSample {
+    /// Sampled incoming direction, normalized and pointing away from the surface
+    pub wi: Vec3,
+    /// Value of the BSDF for the pair of directions
+    pub f: Vec3,
+    /// Probability density of sampling `wi` with respect to solid angle
+    pub pdf: Float,
+    /// Whether `wi` was sampled from a delta distribution such as a perfect mirror. Specular
+    /// samples can not be evaluated through `eval` and `pdf`, their `f` and `pdf` are only
+    /// meaningful relative to each other.
+    pub specular: bool,
+}
+
+impl BsdfSample {
+    /// Returns the factor the path throughput gets multiplied by when following this sample
+    pub fn weight(&self, normal: Vec3) -> Vec3 {
+        self.f * self.wi.dot(normal).abs() / self.pdf
+    }
 }
 
+/// Trait describes how light interacts with a surface. Directions passed to and returned by the
+/// BSDF methods are normalized and point away from the surface, `wo` being the direction towards
+/// the viewer and `wi` the direction light arrives from.
 pub trait Material: Sync + DynClone + Send + DebugTrait {
+    /// Samples an incoming direction for the outgoing direction `wo` with the random numbers of
+    /// `rng`, returns `None` if the material absorbs all light
+    fn sample(
+        &self,
+        _wo: Vec3,
+        _hit_record: &HitRecord,
+        _rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        None
+    }
+
+    /// Evaluates the BSDF for a pair of directions, delta lobes always evaluate to black
+    fn eval(&self, _wo: Vec3, _wi: Vec3, _hit_record: &HitRecord) -> Vec3 {
+        Vec3::new()
+    }
+
+    /// Returns the probability density `sample` would pick `wi` with for the outgoing direction
+    /// `wo`, delta lobes always have a density of zero
+    fn pdf(&self, _wo: Vec3, _wi: Vec3, _hit_record: &HitRecord) -> Float {
+        0.0
+    }
+
+    /// Scatters an incoming ray by sampling the BSDF, returns false if the ray got absorbed
     fn scatter(
         &self,
         ray_in: &Ray,
         hit_record: &HitRecord,
         attenuation: &mut Vec3,
         scattered: &mut Ray,
-    ) -> bool;
-    fn emitted(&self, _: f64, _: f64, _: Vec3) -> Vec3 {
+    ) -> bool {
+        let wo = -ray_in.direction().unit_vector();
+        match self.sample(wo, hit_record, &mut rand::thread_rng()) {
+            Some(sample) if sample.pdf > 0.0 => {
+                attenuation.update(sample.weight(hit_record.normal));
+                *scattered = if sample.specular {
+                    hit_record.spawn_specular_ray(ray_in, sample.wi)
+                } else {
+                    hit_record.spawn_ray(ray_in, sample.wi)
+                };
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn emitted(&self, _: Float, _: Float, _: Vec3) -> Vec3 {
         Vec3::with_values(0.0, 0.0, 0.0)
     }
+
+    /// Returns the medium filling the inside of objects made of this material, if light can
+    /// travel through them
+    fn medium(&self) -> Option<Medium> {
+        None
+    }
 }
 
 dyn_clone::clone_trait_object!(Material);
 
+/// Materials shared between several objects, such as the named materials of a map, forward to
+/// the one material they hold, so cloning them never duplicates its textures
+impl Material for Arc<dyn Material> {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        self.as_ref().sample(wo, hit_record, rng)
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        self.as_ref().eval(wo, wi, hit_record)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        self.as_ref().pdf(wo, wi, hit_record)
+    }
+
+    fn scatter(
+        &self,
+        ray_in: &Ray,
+        hit_record: &HitRecord,
+        attenuation: &mut Vec3,
+        scattered: &mut Ray,
+    ) -> bool {
+        self.as_ref()
+            .scatter(ray_in, hit_record, attenuation, scattered)
+    }
+
+    fn emitted(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.as_ref().emitted(u, v, p)
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        self.as_ref().medium()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Lambertian {
     albedo: Box<dyn Texture>,
@@ -54,33 +249,238 @@ impl Lambertian {
 }
 
 impl Material for Lambertian {
-    fn scatter(
+    fn sample(
         &self,
-        ray: &Ray,
+        wo: Vec3,
         hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
-        let target = hit_record.p + hit_record.normal + Ray::random_in_sphere();
-        scattered.update(Ray::with_values(
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let wi = Onb::from_w(hit_record.normal).local(Ray::random_cosine_direction(rng));
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi, hit_record),
+            pdf: self.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
+
+    fn eval(&self, _: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        if wi.dot(hit_record.normal) <= 0.0 {
+            return Vec3::new();
+        }
+        self.albedo.filtered(
+            hit_record.u,
+            hit_record.v,
             hit_record.p,
-            target - hit_record.p,
-            None,
-            ray.3,
-        ));
-        attenuation.update(self.albedo.value(hit_record.u, hit_record.v, hit_record.p));
-        true
+            &hit_record.footprint,
+        ) / PI
+    }
+
+    fn pdf(&self, _: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        wi.dot(hit_record.normal).max(0.0) / PI
+    }
+}
+
+/// OrenNayar is a rough diffuse surface made of V-shaped Lambertian facets whose slopes have a
+/// standard deviation of `sigma` degrees. Compared to `Lambertian` it looks flatter and scatters
+/// more light back towards the viewer at grazing angles, as clay or concrete do. Uses the
+/// qualitative model from Oren and Nayar's "Generalization of Lambert's Reflectance Model".
+#[derive(Clone, Debug)]
+pub struct OrenNayar {
+    albedo: Box<dyn Texture>,
+    a: Float,
+    b: Float,
+}
+
+impl OrenNayar {
+    pub fn new(albedo: Box<dyn Texture>, sigma: Float) -> Self {
+        let sigma = sigma.to_radians();
+        let sigma2 = sigma * sigma;
+        Self {
+            albedo,
+            a: 1.0 - sigma2 / (2.0 * (sigma2 + 0.33)),
+            b: 0.45 * sigma2 / (sigma2 + 0.09),
+        }
+    }
+}
+
+impl Material for OrenNayar {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let wi = Onb::from_w(hit_record.normal).local(Ray::random_cosine_direction(rng));
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi, hit_record),
+            pdf: self.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        let frame = Onb::from_w(hit_record.normal);
+        let wo = frame.to_local(wo);
+        let wi = frame.to_local(wi);
+        if wi.z() <= 0.0 || wo.z() <= 0.0 {
+            return Vec3::new();
+        }
+
+        let sin_i = (1.0 - wi.z() * wi.z()).max(0.0).sqrt();
+        let sin_o = (1.0 - wo.z() * wo.z()).max(0.0).sqrt();
+
+        // NOTE: Cosine of the azimuth between both directions, only forward facing pairs get the
+        // retro-reflective boost
+        let max_cos = if sin_i > 1e-4 && sin_o > 1e-4 {
+            ((wi.x() * wo.x() + wi.y() * wo.y()) / (sin_i * sin_o)).max(0.0)
+        } else {
+            0.0
+        };
+
+        // NOTE: Alpha is the larger of the two polar angles and beta the smaller one
+        let (sin_alpha, tan_beta) = if wi.z() > wo.z() {
+            (sin_o, sin_i / wi.z())
+        } else {
+            (sin_i, sin_o / wo.z())
+        };
+
+        self.albedo.filtered(
+            hit_record.u,
+            hit_record.v,
+            hit_record.p,
+            &hit_record.footprint,
+        ) / PI
+            * (self.a + self.b * max_cos * sin_alpha * tan_beta)
+    }
+
+    fn pdf(&self, _: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        wi.dot(hit_record.normal).max(0.0) / PI
+    }
+}
+
+/// LommelSeeliger is the reflectance of dusty, low albedo surfaces such as the Moon, where light
+/// is scattered by particles below the surface rather than by the surface itself. It keeps the
+/// disk of a sphere evenly lit almost up to its edge, instead of darkening towards it as a
+/// `Lambertian` sphere does.
+#[derive(Clone, Debug)]
+pub struct LommelSeeliger {
+    albedo: Box<dyn Texture>,
+}
+
+impl LommelSeeliger {
+    pub fn new(albedo: Box<dyn Texture>) -> Self {
+        Self { albedo }
+    }
+}
+
+impl Material for LommelSeeliger {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let wi = Onb::from_w(hit_record.normal).local(Ray::random_cosine_direction(rng));
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi, hit_record),
+            pdf: self.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        let cos_i = wi.dot(hit_record.normal);
+        let cos_o = wo.dot(hit_record.normal);
+        if cos_i <= 0.0 || cos_o <= 0.0 {
+            return Vec3::new();
+        }
+        self.albedo.filtered(
+            hit_record.u,
+            hit_record.v,
+            hit_record.p,
+            &hit_record.footprint,
+        ) / (4.0 * PI * (cos_i + cos_o))
+    }
+
+    fn pdf(&self, _: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        wi.dot(hit_record.normal).max(0.0) / PI
+    }
+}
+
+/// Velvet is cloth, a diffuse base under a sheen lobe that catches the light at grazing angles the
+/// way fibers sticking out of the fabric do. The sheen follows the "Charlie" distribution of Conty
+/// and Kulla's "Production Friendly Microfacet Sheen BRDF", with Neubelt and Pettineo's
+/// visibility term, and is added on top of the diffuse reflection.
+#[derive(Clone, Debug)]
+pub struct Velvet {
+    albedo: Box<dyn Texture>,
+    sheen: Box<dyn Texture>,
+    alpha: Float,
+}
+
+impl Velvet {
+    pub fn new(albedo: Box<dyn Texture>, sheen: Box<dyn Texture>, roughness: Float) -> Self {
+        Self {
+            albedo,
+            sheen,
+            alpha: roughness_to_alpha(roughness).max(0.01),
+        }
+    }
+}
+
+impl Material for Velvet {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let wi = Onb::from_w(hit_record.normal).local(Ray::random_cosine_direction(rng));
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi, hit_record),
+            pdf: self.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        let cos_i = wi.dot(hit_record.normal);
+        let cos_o = wo.dot(hit_record.normal);
+        if cos_i <= 0.0 || cos_o <= 0.0 {
+            return Vec3::new();
+        }
+
+        let cos_h = (wo + wi).unit_vector().dot(hit_record.normal);
+        let sin_h = (1.0 - cos_h * cos_h).max(0.0).sqrt();
+        let inv_alpha = 1.0 / self.alpha;
+        let d = (2.0 + inv_alpha) * sin_h.powf(inv_alpha) / (2.0 * PI);
+        let visibility = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+
+        let (u, v, p) = (hit_record.u, hit_record.v, hit_record.p);
+        let footprint = &hit_record.footprint;
+        self.albedo.filtered(u, v, p, footprint) / PI
+            + self.sheen.filtered(u, v, p, footprint) * d * visibility
+    }
+
+    fn pdf(&self, _: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        wi.dot(hit_record.normal).max(0.0) / PI
     }
 }
 
+/// Metal is a mirror reflection jittered inside a sphere of radius `fuzz`. The jitter has no
+/// closed form density, so all samples are treated as specular.
 #[derive(Clone, Debug)]
 pub struct Metal {
     albedo: Box<dyn Texture>,
-    fuzz: f64,
+    fuzz: Float,
 }
 
 impl Metal {
-    pub fn new(albedo: Box<dyn Texture>, fuzz: f64) -> Self {
+    pub fn new(albedo: Box<dyn Texture>, fuzz: Float) -> Self {
         Self {
             albedo,
             fuzz: if fuzz < 1.0 { fuzz } else { 1.0 },
@@ -89,102 +489,1091 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(
+    fn sample(
         &self,
-        ray_in: &Ray,
+        wo: Vec3,
         hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
-        let reflected = reflect(ray_in.direction().unit_vector(), hit_record.normal);
-        scattered.update(Ray::with_values(
-            hit_record.p,
-            reflected + self.fuzz * Ray::random_in_sphere(),
-            Some(ray_in.time()),
-            ray_in.3,
-        ));
-        attenuation.update(self.albedo.value(hit_record.u, hit_record.v, hit_record.p));
-        scattered.direction().dot(hit_record.normal) > 0.0
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let reflected = reflect(-wo, hit_record.normal);
+        let wi = (reflected + self.fuzz * Ray::random_in_sphere(rng)).unit_vector();
+        let cos = wi.dot(hit_record.normal);
+        if cos <= 0.0 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi,
+            f: self.albedo.filtered(
+                hit_record.u,
+                hit_record.v,
+                hit_record.p,
+                &hit_record.footprint,
+            ) / cos,
+            pdf: 1.0,
+            specular: true,
+        })
+    }
+}
+
+/// Struct holds the complex index of refraction of a conductor for the red, green and blue
+/// channels
+#[derive(Clone, Copy, Debug)]
+pub struct ComplexIor {
+    pub eta: Vec3,
+    pub k: Vec3,
+}
+
+impl ComplexIor {
+    pub fn new(eta: Vec3, k: Vec3) -> Self {
+        Self { eta, k }
+    }
+
+    pub fn gold() -> Self {
+        Self::new(
+            Vec3::with_values(0.143119, 0.374957, 1.442479),
+            Vec3::with_values(3.98316, 2.385721, 1.603215),
+        )
+    }
+
+    pub fn copper() -> Self {
+        Self::new(
+            Vec3::with_values(0.200438, 0.924033, 1.102212),
+            Vec3::with_values(3.912949, 2.452848, 2.142188),
+        )
+    }
+
+    pub fn aluminium() -> Self {
+        Self::new(
+            Vec3::with_values(1.65746, 0.880369, 0.521229),
+            Vec3::with_values(9.223869, 6.269523, 4.837001),
+        )
+    }
+
+    pub fn silver() -> Self {
+        Self::new(
+            Vec3::with_values(0.155265, 0.116723, 0.138342),
+            Vec3::with_values(4.828181, 3.12225, 2.146961),
+        )
+    }
+
+    /// Returns the index of refraction at `lambda` nanometers, interpolated between the
+    /// wavelengths the channels stand for
+    pub fn at(&self, lambda: Float) -> Complex {
+        const RED: Float = 630.0;
+        const GREEN: Float = 532.0;
+        const BLUE: Float = 465.0;
+
+        let (channel, t) = if lambda >= GREEN {
+            (0, (lambda - GREEN) / (RED - GREEN))
+        } else {
+            (2, (GREEN - lambda) / (GREEN - BLUE))
+        };
+        let t = t.min(1.0);
+        Complex::new(
+            self.eta[1] + t * (self.eta[channel] - self.eta[1]),
+            self.k[1] + t * (self.k[channel] - self.k[1]),
+        )
+    }
+
+    /// Returns the reflectance of the conductor for light arriving at `cos` to the normal
+    pub fn fresnel(&self, cos: Float) -> Vec3 {
+        Vec3::with_values(
+            fresnel_conductor(cos, self.eta[0], self.k[0]),
+            fresnel_conductor(cos, self.eta[1], self.k[1]),
+            fresnel_conductor(cos, self.eta[2], self.k[2]),
+        )
+    }
+}
+
+/// Conductor is a metal with a GGX microfacet surface and the Fresnel reflectance of its complex
+/// index of refraction. The roughness along the direction of increasing `u` and across it can
+/// differ for brushed looks, and a roughness of zero gives a perfect mirror.
+#[derive(Clone, Debug)]
+pub struct Conductor {
+    ior: ComplexIor,
+    distribution: TrowbridgeReitz,
+    film: Option<ThinFilm>,
+}
+
+impl Conductor {
+    /// Creates a conductor from its perceptual roughness along the tangent and the bitangent
+    pub fn new(ior: ComplexIor, roughness_u: Float, roughness_v: Float) -> Self {
+        Self {
+            ior,
+            distribution: TrowbridgeReitz::new(
+                roughness_to_alpha(roughness_u),
+                roughness_to_alpha(roughness_v),
+            ),
+            film: None,
+        }
+    }
+
+    /// Coats the conductor with a thin transparent film, such as the oxide layer of anodized
+    /// metal
+    pub fn thin_film(mut self, film: ThinFilm) -> Self {
+        self.film = Some(film);
+        self
+    }
+
+    /// Returns the shading frame with its tangent along `dpdu`, which the brushing follows
+    fn frame(hit_record: &HitRecord) -> Onb {
+        Onb::from_w_tangent(hit_record.normal, hit_record.dpdu)
+    }
+
+    fn fresnel(&self, cos: Float, hit_record: &HitRecord) -> Vec3 {
+        match self.film {
+            Some(film) => film.reflectance(
+                cos,
+                hit_record.surrounding_ior,
+                |lambda| self.ior.at(lambda),
+                hit_record.wavelength,
+            ),
+            None => self.ior.fresnel(cos),
+        }
+    }
+}
+
+impl Material for Conductor {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let frame = Self::frame(hit_record);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z() <= 0.0 {
+            return None;
+        }
+
+        if self.distribution.is_smooth() {
+            let wi = reflect(-wo, hit_record.normal);
+            return Some(BsdfSample {
+                wi,
+                f: self.fresnel(wo_local.z(), hit_record) / wo_local.z(),
+                pdf: 1.0,
+                specular: true,
+            });
+        }
+
+        let wh = self.distribution.sample_wh(wo_local, rng);
+        let wi_local = reflect(-wo_local, wh);
+        if wi_local.z() <= 0.0 {
+            return None;
+        }
+
+        let wi = frame.local(wi_local);
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi, hit_record),
+            pdf: self.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        if self.distribution.is_smooth() {
+            return Vec3::new();
+        }
+
+        let frame = Self::frame(hit_record);
+        let wo = frame.to_local(wo);
+        let wi = frame.to_local(wi);
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3::new();
+        }
+
+        let wh = (wo + wi).unit_vector();
+        self.fresnel(wi.dot(wh), hit_record) * self.distribution.d(wh) * self.distribution.g(wo, wi)
+            / (4.0 * wo.z() * wi.z())
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        if self.distribution.is_smooth() {
+            return 0.0;
+        }
+
+        let frame = Self::frame(hit_record);
+        let wo = frame.to_local(wo);
+        let wi = frame.to_local(wi);
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return 0.0;
+        }
+
+        let wh = (wo + wi).unit_vector();
+        self.distribution.pdf(wo, wh) / (4.0 * wo.dot(wh))
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Dielectric {
-    ref_index: f64,
+    medium: Medium,
+    film: Option<ThinFilm>,
 }
 
 impl Dielectric {
-    pub fn new(ref_index: f64) -> Self {
-        Self { ref_index }
+    pub fn new(ref_index: Float) -> Self {
+        Self::with_medium(Medium::new(ref_index))
+    }
+
+    /// Creates a dielectric filled with `medium`, which may absorb light and take priority over
+    /// other media it overlaps
+    pub fn with_medium(medium: Medium) -> Self {
+        Self { medium, film: None }
+    }
+
+    /// Coats the dielectric with a thin transparent film. A soap bubble is a film of water over a
+    /// dielectric with the index of refraction of air.
+    pub fn thin_film(mut self, film: ThinFilm) -> Self {
+        self.film = Some(film);
+        self
+    }
+
+    /// Samples the interface as coated by `film`, whose reflectance varies with the color
+    fn sample_film(
+        &self,
+        film: ThinFilm,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> BsdfSample {
+        let normal = hit_record.normal;
+        let cos = wo.dot(normal);
+        let ior = self.medium.ior_at(hit_record.wavelength);
+        let (n_i, n_t) = if hit_record.front_face {
+            (hit_record.surrounding_ior, ior)
+        } else {
+            (ior, hit_record.surrounding_ior)
+        };
+
+        let mut refracted = Vec3::new();
+        let reflectance = if refract(-wo, normal, n_i / n_t, &mut refracted) {
+            film.reflectance(cos, n_i, |_| Complex::real(n_t), hit_record.wavelength)
+        } else {
+            Vec3::with_values(1.0, 1.0, 1.0)
+        };
+
+        // NOTE: Reflection is picked with the average reflectance, the throughput carries the
+        // color of the interference
+        let reflect_prob = (reflectance.x() + reflectance.y() + reflectance.z()) / 3.0;
+        let (wi, f, pdf) = if rng.gen::<Float>() < reflect_prob {
+            (reflect(-wo, normal), reflectance, reflect_prob)
+        } else {
+            let eta = n_t / n_i;
+            (
+                refracted.unit_vector(),
+                (Vec3::with_values(1.0, 1.0, 1.0) - reflectance) / (eta * eta),
+                1.0 - reflect_prob,
+            )
+        };
+
+        BsdfSample {
+            wi,
+            f: f / wi.dot(normal).abs(),
+            pdf,
+            specular: true,
+        }
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(
+    fn sample(
         &self,
-        ray_in: &Ray,
+        wo: Vec3,
         hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
-        let mut rng = rand::thread_rng();
-        let outward_normal;
-        let ni_over_nt;
-        let reflect_prob;
-        let cos;
-        let reflected = reflect(ray_in.direction(), hit_record.normal);
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        if let Some(film) = self.film {
+            return Some(self.sample_film(film, wo, hit_record, rng));
+        }
+
+        Some(sample_smooth_dielectric(
+            wo,
+            relative_ior(&self.medium, hit_record),
+            hit_record,
+            rng,
+        ))
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        Some(self.medium)
+    }
+}
+
+/// RoughDielectric is a glass with a GGX microfacet surface that both reflects and transmits
+/// light, following Walter et al. "Microfacet Models for Refraction through Rough Surfaces".
+/// Transmitted radiance is scaled by the squared ratio of the indices of refraction, which makes
+/// the BSDF non-symmetric. A roughness of zero gives the smooth glass of `Dielectric`.
+#[derive(Clone, Debug)]
+pub struct RoughDielectric {
+    medium: Medium,
+    distribution: TrowbridgeReitz,
+}
+
+impl RoughDielectric {
+    pub fn new(ref_index: Float, roughness: Float) -> Self {
+        Self::with_medium(Medium::new(ref_index), roughness)
+    }
+
+    /// Creates a rough dielectric filled with `medium`, which may absorb light and take priority
+    /// over other media it overlaps
+    pub fn with_medium(medium: Medium, roughness: Float) -> Self {
+        let alpha = roughness_to_alpha(roughness);
+        Self {
+            medium,
+            distribution: TrowbridgeReitz::new(alpha, alpha),
+        }
+    }
+
+    /// Returns the microfacet normal that scatters `wo` into `wi` in the local frame, or `None`
+    /// if the pair can not be connected through a facet visible from both directions
+    fn half_vector(wo: Vec3, wi: Vec3, eta: Float) -> Option<Vec3> {
+        if wo.z() == 0.0 || wi.z() == 0.0 {
+            return None;
+        }
+
+        let wh = if wi.z() > 0.0 { wo + wi } else { wo + eta * wi };
+        if wh.squared_len() == 0.0 {
+            return None;
+        }
+
+        let wh = wh.unit_vector();
+        let wh = if wh.z() < 0.0 { -wh } else { wh };
+        if wh.dot(wo) <= 0.0 || wh.dot(wi) * wi.z() <= 0.0 {
+            return None;
+        }
+        Some(wh)
+    }
+}
+
+impl Material for RoughDielectric {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let eta = relative_ior(&self.medium, hit_record);
+        let frame = Onb::from_w(hit_record.normal);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z() <= 0.0 {
+            return None;
+        }
+
+        if self.distribution.is_smooth() {
+            return Some(sample_smooth_dielectric(wo, eta, hit_record, rng));
+        }
+
+        let wh = self.distribution.sample_wh(wo_local, rng);
+        let reflectance = fresnel_dielectric(wo_local.dot(wh), eta);
         let mut refracted = Vec3::new();
-        attenuation.update(Vec3::with_values(1.0, 1.0, 1.0));
+        let wi_local = if rng.gen::<Float>() < reflectance
+            || !refract(-wo_local, wh, 1.0 / eta, &mut refracted)
+        {
+            let wi_local = reflect(-wo_local, wh);
+            if wi_local.z() <= 0.0 {
+                return None;
+            }
+            wi_local
+        } else {
+            let wi_local = refracted.unit_vector();
+            if wi_local.z() >= 0.0 {
+                return None;
+            }
+            wi_local
+        };
+
+        let wi = frame.local(wi_local);
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi, hit_record),
+            pdf: self.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
 
-        if ray_in.direction().dot(hit_record.normal) > 0.0 {
-            outward_normal = -hit_record.normal;
-            ni_over_nt = self.ref_index;
-            cos = self.ref_index * ray_in.direction().dot(hit_record.normal)
-                / ray_in.direction().len();
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        if self.distribution.is_smooth() {
+            return Vec3::new();
+        }
+
+        let eta = relative_ior(&self.medium, hit_record);
+        let frame = Onb::from_w(hit_record.normal);
+        let wo = frame.to_local(wo);
+        let wi = frame.to_local(wi);
+        let wh = match Self::half_vector(wo, wi, eta) {
+            Some(wh) => wh,
+            None => return Vec3::new(),
+        };
+
+        let reflectance = fresnel_dielectric(wo.dot(wh), eta);
+        let dg = self.distribution.d(wh) * self.distribution.g(wo, wi);
+        let f = if wi.z() > 0.0 {
+            dg * reflectance / (4.0 * wo.z() * wi.z())
+        } else {
+            let denom = wi.dot(wh) + wo.dot(wh) / eta;
+            let f = dg * (1.0 - reflectance) * (wi.dot(wh) * wo.dot(wh)).abs()
+                / (denom * denom * wi.z() * wo.z()).abs();
+            // NOTE: Radiance is compressed into a smaller solid angle when it enters the denser
+            // medium, the adjoint direction would skip this scaling
+            f / (eta * eta)
+        };
+        Vec3::with_values(f, f, f)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        if self.distribution.is_smooth() {
+            return 0.0;
+        }
+
+        let eta = relative_ior(&self.medium, hit_record);
+        let frame = Onb::from_w(hit_record.normal);
+        let wo = frame.to_local(wo);
+        let wi = frame.to_local(wi);
+        let wh = match Self::half_vector(wo, wi, eta) {
+            Some(wh) => wh,
+            None => return 0.0,
+        };
+
+        let reflectance = fresnel_dielectric(wo.dot(wh), eta);
+        if wi.z() > 0.0 {
+            self.distribution.pdf(wo, wh) / (4.0 * wo.dot(wh)) * reflectance
+        } else {
+            let denom = wi.dot(wh) + wo.dot(wh) / eta;
+            let dwh_dwi = wi.dot(wh).abs() / (denom * denom);
+            self.distribution.pdf(wo, wh) * dwh_dwi * (1.0 - reflectance)
+        }
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        Some(self.medium)
+    }
+}
+
+/// Principled is an uber material following Disney's principled BSDF, blending a diffuse base with
+/// sheen, a tinted GGX specular lobe, metal, a GTR1 clearcoat and rough glass transmission. Every
+/// parameter is read from a texture, so any of them can vary over the surface. Rays hitting the
+/// inside of a transmissive object only see the glass lobe.
+#[derive(Clone, Debug)]
+pub struct Principled {
+    base_color: Box<dyn Texture>,
+    metallic: Box<dyn Texture>,
+    roughness: Box<dyn Texture>,
+    specular: Box<dyn Texture>,
+    specular_tint: Box<dyn Texture>,
+    sheen: Box<dyn Texture>,
+    clearcoat: Box<dyn Texture>,
+    clearcoat_gloss: Box<dyn Texture>,
+    transmission: Option<Box<dyn Texture>>,
+    emission: Option<Box<dyn Texture>>,
+    ior: Float,
+}
+
+/// Parameters of a principled material looked up at a single hit point
+struct PrincipledLobes {
+    base_color: Vec3,
+    metallic: Float,
+    roughness: Float,
+    specular: Float,
+    specular_tint: Float,
+    sheen: Float,
+    distribution: TrowbridgeReitz,
+    clearcoat_distribution: Gtr1,
+    glass: RoughDielectric,
+    /// Weights of the diffuse, specular, transmission and clearcoat lobes
+    weights: [Float; 4],
+}
+
+impl Principled {
+    /// Creates a dielectric principled material with the default parameters of Disney's BSDF
+    pub fn new(base_color: Box<dyn Texture>) -> Self {
+        Self {
+            base_color,
+            metallic: gray_texture(0.0),
+            roughness: gray_texture(0.5),
+            specular: gray_texture(0.5),
+            specular_tint: gray_texture(0.0),
+            sheen: gray_texture(0.0),
+            clearcoat: gray_texture(0.0),
+            clearcoat_gloss: gray_texture(1.0),
+            transmission: None,
+            emission: None,
+            ior: 1.5,
+        }
+    }
+
+    pub fn metallic(mut self, metallic: Box<dyn Texture>) -> Self {
+        self.metallic = metallic;
+        self
+    }
+
+    pub fn roughness(mut self, roughness: Box<dyn Texture>) -> Self {
+        self.roughness = roughness;
+        self
+    }
+
+    pub fn specular(mut self, specular: Box<dyn Texture>) -> Self {
+        self.specular = specular;
+        self
+    }
+
+    pub fn specular_tint(mut self, specular_tint: Box<dyn Texture>) -> Self {
+        self.specular_tint = specular_tint;
+        self
+    }
+
+    pub fn sheen(mut self, sheen: Box<dyn Texture>) -> Self {
+        self.sheen = sheen;
+        self
+    }
+
+    pub fn clearcoat(mut self, clearcoat: Box<dyn Texture>) -> Self {
+        self.clearcoat = clearcoat;
+        self
+    }
+
+    pub fn clearcoat_gloss(mut self, clearcoat_gloss: Box<dyn Texture>) -> Self {
+        self.clearcoat_gloss = clearcoat_gloss;
+        self
+    }
+
+    /// Makes the material transmissive where `transmission` is above zero, refracting with the
+    /// index of refraction `ior`
+    pub fn transmission(mut self, transmission: Box<dyn Texture>, ior: Float) -> Self {
+        self.transmission = Some(transmission);
+        self.ior = ior;
+        self
+    }
+
+    pub fn emission(mut self, emission: Box<dyn Texture>) -> Self {
+        self.emission = Some(emission);
+        self
+    }
+
+    fn lobes(&self, hit_record: &HitRecord) -> PrincipledLobes {
+        let (u, v, p) = (hit_record.u, hit_record.v, hit_record.p);
+        let metallic = self.metallic.scalar(u, v, p).clamp(0.0, 1.0);
+        // NOTE: Roughness is kept away from zero so that every lobe can be evaluated, perfect
+        // mirrors and glass are left to the dedicated materials
+        let roughness = self.roughness.scalar(u, v, p).clamp(0.05, 1.0);
+        let transmission = self
+            .transmission
+            .as_ref()
+            .map_or(0.0, |t| t.scalar(u, v, p).clamp(0.0, 1.0));
+        let clearcoat = self.clearcoat.scalar(u, v, p).max(0.0);
+        let clearcoat_gloss = self.clearcoat_gloss.scalar(u, v, p).clamp(0.0, 1.0);
+        let alpha = roughness_to_alpha(roughness);
+
+        let transmission = (1.0 - metallic) * transmission;
+        let weights = if hit_record.front_face || self.transmission.is_none() {
+            [
+                (1.0 - metallic) - transmission,
+                1.0 - transmission,
+                transmission,
+                0.25 * clearcoat,
+            ]
+        } else {
+            [0.0, 0.0, 1.0, 0.0]
+        };
+
+        PrincipledLobes {
+            base_color: self.base_color.filtered(u, v, p, &hit_record.footprint),
+            metallic,
+            roughness,
+            specular: self.specular.scalar(u, v, p).max(0.0),
+            specular_tint: self.specular_tint.scalar(u, v, p).clamp(0.0, 1.0),
+            sheen: self.sheen.scalar(u, v, p).max(0.0),
+            distribution: TrowbridgeReitz::new(alpha, alpha),
+            clearcoat_distribution: Gtr1::new(0.1 + (0.001 - 0.1) * clearcoat_gloss),
+            glass: RoughDielectric::with_medium(Medium::new(self.ior), roughness),
+            weights,
+        }
+    }
+}
+
+impl PrincipledLobes {
+    /// Returns the probability of sampling each lobe
+    fn probabilities(&self) -> [Float; 4] {
+        let total: Float = self.weights.iter().sum();
+        let mut probabilities = self.weights;
+        for p in probabilities.iter_mut() {
+            *p /= total;
+        }
+        probabilities
+    }
+
+    /// Returns the base color normalized to unit luminance, the hue that tints specular and sheen
+    fn tint(&self) -> Vec3 {
+        let luminance =
+            0.3 * self.base_color.r() + 0.6 * self.base_color.g() + 0.1 * self.base_color.b();
+        if luminance > 0.0 {
+            self.base_color / luminance
         } else {
-            outward_normal = hit_record.normal;
-            ni_over_nt = 1.0 / self.ref_index;
-            cos = -ray_in.direction().dot(hit_record.normal) / ray_in.direction().len();
+            Vec3::with_values(1.0, 1.0, 1.0)
         }
+    }
 
-        if refract(
-            ray_in.direction(),
-            outward_normal,
-            ni_over_nt,
-            &mut refracted,
-        ) {
-            reflect_prob = schlick(cos, self.ref_index);
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        let frame = Onb::from_w(hit_record.normal);
+        let reflection = self.eval_reflection(frame.to_local(wo), frame.to_local(wi));
+        if self.weights[2] > 0.0 {
+            reflection + self.weights[2] * self.glass.eval(wo, wi, hit_record)
         } else {
-            reflect_prob = 1.0;
+            reflection
         }
+    }
 
-        if rng.gen::<f64>() < reflect_prob {
-            scattered.update(Ray::with_values(hit_record.p, reflected, None, ray_in.3));
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        let probabilities = self.probabilities();
+        let frame = Onb::from_w(hit_record.normal);
+        let reflection = self.pdf_reflection(frame.to_local(wo), frame.to_local(wi));
+        let transmission = if probabilities[2] > 0.0 {
+            self.glass.pdf(wo, wi, hit_record)
         } else {
-            scattered.update(Ray::with_values(hit_record.p, refracted, None, ray_in.3));
+            0.0
+        };
+
+        probabilities[0] * reflection[0]
+            + probabilities[1] * reflection[1]
+            + probabilities[2] * transmission
+            + probabilities[3] * reflection[2]
+    }
+
+    /// Evaluates the lobes that only reflect, for directions in the local shading frame
+    fn eval_reflection(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return Vec3::new();
+        }
+
+        let white = Vec3::with_values(1.0, 1.0, 1.0);
+        let wh = (wo + wi).unit_vector();
+        let cos_d = wi.dot(wh);
+        let schlick_weight = |cos: Float| (1.0 - cos).clamp(0.0, 1.0).powi(5);
+
+        // NOTE: Burley's diffuse gets brighter towards grazing angles on rough surfaces and
+        // darker on smooth ones
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * schlick_weight(wi.z()))
+            * (1.0 + (fd90 - 1.0) * schlick_weight(wo.z()));
+        let diffuse = self.base_color * fd / PI;
+        let sheen_color = white + 0.5 * (self.tint() - white);
+        let sheen = self.sheen * schlick_weight(cos_d) * sheen_color;
+
+        let dielectric_f0 =
+            self.specular * 0.08 * (white + self.specular_tint * (self.tint() - white));
+        let f0 = dielectric_f0 + self.metallic * (self.base_color - dielectric_f0);
+        let fresnel = f0 + (white - f0) * schlick_weight(cos_d);
+        let specular = fresnel * self.distribution.d(wh) * self.distribution.g(wo, wi)
+            / (4.0 * wo.z() * wi.z());
+
+        let clearcoat_fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+        // NOTE: The clearcoat weight already holds its strength, and drops it on hits from below
+        let clearcoat = self.weights[3]
+            * clearcoat_fresnel
+            * self.clearcoat_distribution.d(wh)
+            * smith_g1(wo, 0.25)
+            * smith_g1(wi, 0.25)
+            / (4.0 * wo.z() * wi.z());
+
+        self.weights[0] * (diffuse + sheen)
+            + self.weights[1] * specular
+            + Vec3::with_values(clearcoat, clearcoat, clearcoat)
+    }
+
+    /// Returns the densities the reflecting lobes sample `wi` with, in the local shading frame
+    fn pdf_reflection(&self, wo: Vec3, wi: Vec3) -> [Float; 3] {
+        if wo.z() <= 0.0 || wi.z() <= 0.0 {
+            return [0.0; 3];
+        }
+
+        let wh = (wo + wi).unit_vector();
+        [
+            wi.z() / PI,
+            self.distribution.pdf(wo, wh) / (4.0 * wo.dot(wh)),
+            self.clearcoat_distribution.pdf(wh) / (4.0 * wo.dot(wh)),
+        ]
+    }
+}
+
+impl Material for Principled {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let lobes = self.lobes(hit_record);
+        let probabilities = lobes.probabilities();
+        let frame = Onb::from_w(hit_record.normal);
+        let wo_local = frame.to_local(wo);
+        if wo_local.z() <= 0.0 {
+            return None;
         }
-        true
+
+        let mut pick = rng.gen::<Float>();
+        let mut lobe = 0;
+        while lobe < 3 && pick >= probabilities[lobe] {
+            pick -= probabilities[lobe];
+            lobe += 1;
+        }
+
+        let wi = match lobe {
+            0 => frame.local(Ray::random_cosine_direction(rng)),
+            1 => {
+                let wh = lobes.distribution.sample_wh(wo_local, rng);
+                frame.local(reflect(-wo_local, wh))
+            }
+            2 => lobes.glass.sample(wo, hit_record, rng)?.wi,
+            _ => {
+                let wh = lobes.clearcoat_distribution.sample_wh(rng);
+                frame.local(reflect(-wo_local, wh))
+            }
+        };
+        // NOTE: Reflections mirrored below the surface are lost, the glass lobe would otherwise
+        // give them the density of a transmission
+        if lobe != 2 && wi.dot(hit_record.normal) <= 0.0 {
+            return None;
+        }
+
+        Some(BsdfSample {
+            wi,
+            f: lobes.eval(wo, wi, hit_record),
+            pdf: lobes.pdf(wo, wi, hit_record),
+            specular: false,
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        self.lobes(hit_record).eval(wo, wi, hit_record)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        self.lobes(hit_record).pdf(wo, wi, hit_record)
+    }
+
+    fn emitted(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.emission
+            .as_ref()
+            .map_or_else(Vec3::new, |emission| emission.value(u, v, p))
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        self.transmission.as_ref().map(|_| Medium::new(self.ior))
     }
 }
 
+/// Mix blends two materials, picking the second one with the probability given by `factor`. A
+/// texture mask makes it possible to lay patches of one material over another.
 #[derive(Clone, Debug)]
-pub struct Blank;
+pub struct Mix {
+    first: Box<dyn Material>,
+    second: Box<dyn Material>,
+    factor: Box<dyn Texture>,
+}
+
+impl Mix {
+    pub fn new(
+        first: Box<dyn Material>,
+        second: Box<dyn Material>,
+        factor: Box<dyn Texture>,
+    ) -> Self {
+        Self {
+            first,
+            second,
+            factor,
+        }
+    }
+
+    /// Returns the weight of the second material at the hit point
+    fn factor(&self, hit_record: &HitRecord) -> Float {
+        self.factor
+            .scalar(hit_record.u, hit_record.v, hit_record.p)
+            .clamp(0.0, 1.0)
+    }
+}
+
+impl Material for Mix {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let t = self.factor(hit_record);
+        let (material, weight) = if rng.gen::<Float>() < t {
+            (&self.second, t)
+        } else {
+            (&self.first, 1.0 - t)
+        };
+
+        let sample = material.sample(wo, hit_record, rng)?;
+        if sample.specular {
+            // NOTE: Delta lobes can not be evaluated, their value and density are both scaled by
+            // the probability of picking the material
+            return Some(BsdfSample {
+                f: sample.f * weight,
+                pdf: sample.pdf * weight,
+                ..sample
+            });
+        }
+
+        Some(BsdfSample {
+            f: self.eval(wo, sample.wi, hit_record),
+            pdf: self.pdf(wo, sample.wi, hit_record),
+            ..sample
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        let t = self.factor(hit_record);
+        (1.0 - t) * self.first.eval(wo, wi, hit_record) + t * self.second.eval(wo, wi, hit_record)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        let t = self.factor(hit_record);
+        (1.0 - t) * self.first.pdf(wo, wi, hit_record) + t * self.second.pdf(wo, wi, hit_record)
+    }
+
+    fn emitted(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        let t = self.factor.scalar(u, v, p).clamp(0.0, 1.0);
+        (1.0 - t) * self.first.emitted(u, v, p) + t * self.second.emitted(u, v, p)
+    }
 
-impl Blank {
-    pub fn new() -> Self {
-        Self
+    /// Objects blending two media are filled with the medium of the first material that has one
+    fn medium(&self) -> Option<Medium> {
+        self.first.medium().or_else(|| self.second.medium())
     }
 }
 
-impl Default for Blank {
-    fn default() -> Self {
-        Self::new()
+/// Maximum number of times light is followed between a coating and the material underneath it,
+/// light still trapped after that is considered absorbed
+const MAX_COAT_BOUNCES: usize = 32;
+
+/// Coated puts a dielectric clearcoat over any base material, as on car paint or varnished wood.
+/// Light refracted through the coat is followed as it bounces between the base and the underside
+/// of the coat, so that light reflected back down by the coat is not lost. The bounces have no
+/// closed form density, so all samples are treated as specular. Hits from the inside of an object
+/// only see the base material.
+#[derive(Clone, Debug)]
+pub struct Coated {
+    base: Box<dyn Material>,
+    coat: RoughDielectric,
+}
+
+impl Coated {
+    pub fn new(base: Box<dyn Material>, ior: Float, roughness: Float) -> Self {
+        Self {
+            base,
+            coat: RoughDielectric::new(ior, roughness),
+        }
     }
 }
 
-impl Material for Blank {
-    fn scatter(&self, _: &Ray, _: &HitRecord, _: &mut Vec3, _: &mut Ray) -> bool {
-        false
+impl Material for Coated {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        if !hit_record.front_face {
+            return self.base.sample(wo, hit_record, rng);
+        }
+
+        let normal = hit_record.normal;
+        let mut throughput = Vec3::with_values(1.0, 1.0, 1.0);
+        let mut under_coat = *hit_record;
+        under_coat.normal = -normal;
+        under_coat.front_face = false;
+
+        // NOTE: Light either gets reflected by the coat right away or enters the layer under it
+        let entering = self.coat.sample(wo, hit_record, rng)?;
+        if entering.pdf <= 0.0 {
+            return None;
+        }
+        throughput *= entering.weight(normal);
+        let mut wi = entering.wi;
+
+        for _ in 0..MAX_COAT_BOUNCES {
+            if wi.dot(normal) > 0.0 {
+                return Some(BsdfSample {
+                    wi,
+                    f: throughput / wi.dot(normal),
+                    pdf: 1.0,
+                    specular: true,
+                });
+            }
+
+            let scattered = self.base.sample(-wi, hit_record, rng)?;
+            if scattered.pdf <= 0.0 {
+                return None;
+            }
+            throughput *= scattered.weight(normal);
+            if scattered.wi.dot(normal) <= 0.0 {
+                // NOTE: Light transmitted by the base leaves through the bottom of the surface
+                return Some(BsdfSample {
+                    wi: scattered.wi,
+                    f: throughput / scattered.wi.dot(normal).abs(),
+                    pdf: 1.0,
+                    specular: true,
+                });
+            }
+
+            let leaving = self.coat.sample(-scattered.wi, &under_coat, rng)?;
+            if leaving.pdf <= 0.0 {
+                return None;
+            }
+            throughput *= leaving.weight(normal);
+            wi = leaving.wi;
+        }
+        None
+    }
+
+    fn emitted(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.base.emitted(u, v, p)
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        self.base.medium()
+    }
+}
+
+/// Step in texture coordinates used to take the slope of bump maps
+const BUMP_DELTA: Float = 5e-4;
+
+/// Bumped perturbs the shading normal of another material with a tangent space normal map, a
+/// grayscale bump map or both, adding surface detail without extra geometry. Rays still leave
+/// from the geometric surface, so strong perturbations can darken or leak light at grazing
+/// angles.
+///
+/// All layers of the wrapped material are shaded with the perturbed normal, so the wrapper goes
+/// outside of mixes and coats. Wrapping only their inner materials leaves the other layers smooth
+/// and shades differently.
+#[derive(Clone, Debug)]
+pub struct Bumped {
+    material: Box<dyn Material>,
+    normal_map: Option<Box<dyn Texture>>,
+    bump_map: Option<Box<dyn Texture>>,
+    bump_scale: Float,
+}
+
+impl Bumped {
+    pub fn new(material: Box<dyn Material>) -> Self {
+        Self {
+            material,
+            normal_map: None,
+            bump_map: None,
+            bump_scale: 1.0,
+        }
+    }
+
+    /// Sets the normal map, whose colors encode directions in the frame of the tangent, the
+    /// bitangent and the normal as in most normal map images
+    pub fn normal_map(mut self, normal_map: Box<dyn Texture>) -> Self {
+        self.normal_map = Some(normal_map);
+        self
+    }
+
+    /// Sets the bump map, whose values times `scale` give the height the surface is displaced
+    /// by along its normal, in scene units
+    pub fn bump_map(mut self, bump_map: Box<dyn Texture>, scale: Float) -> Self {
+        self.bump_map = Some(bump_map);
+        self.bump_scale = scale;
+        self
+    }
+
+    /// Returns a copy of `hit_record` with the normal perturbed by the maps
+    fn shading<'a>(&self, hit_record: &HitRecord<'a>) -> HitRecord<'a> {
+        let (u, v, p) = (hit_record.u, hit_record.v, hit_record.p);
+        let mut normal = if hit_record.front_face {
+            hit_record.normal
+        } else {
+            -hit_record.normal
+        };
+
+        if let Some(normal_map) = &self.normal_map {
+            let tangent = (hit_record.dpdu - normal.dot(hit_record.dpdu) * normal).unit_vector();
+            let mut bitangent = normal.cross(tangent);
+            if bitangent.dot(hit_record.dpdv) < 0.0 {
+                bitangent = -bitangent;
+            }
+            let direction = 2.0 * normal_map.value(u, v, p) - Vec3::with_values(1.0, 1.0, 1.0);
+            normal = (direction.x() * tangent + direction.y() * bitangent + direction.z() * normal)
+                .unit_vector();
+        }
+
+        if let Some(bump_map) = &self.bump_map {
+            // NOTE: The surface is displaced along the normal by the height, and the normal of
+            // the displaced surface is taken from the slopes of the height, see PBR 9.3
+            let dpdu = hit_record.dpdu - normal.dot(hit_record.dpdu) * normal;
+            let dpdv = hit_record.dpdv - normal.dot(hit_record.dpdv) * normal;
+            let height = bump_map.scalar(u, v, p);
+            let height_u = bump_map.scalar(u + BUMP_DELTA, v, p + BUMP_DELTA * hit_record.dpdu);
+            let height_v = bump_map.scalar(u, v + BUMP_DELTA, p + BUMP_DELTA * hit_record.dpdv);
+            let slope_u = self.bump_scale * (height_u - height) / BUMP_DELTA;
+            let slope_v = self.bump_scale * (height_v - height) / BUMP_DELTA;
+
+            let bumped = (dpdu + slope_u * normal)
+                .cross(dpdv + slope_v * normal)
+                .unit_vector();
+            normal = if bumped.dot(normal) < 0.0 {
+                -bumped
+            } else {
+                bumped
+            };
+        }
+
+        let mut shading = *hit_record;
+        shading.normal = if hit_record.front_face {
+            normal
+        } else {
+            -normal
+        };
+        shading
+    }
+
+    /// Returns the factor that moves the cosine of the shading normal into the BSDF, as the
+    /// throughput is weighted with the cosine of the geometric normal
+    fn cos_ratio(wi: Vec3, shading: &HitRecord, hit_record: &HitRecord) -> Float {
+        let cos = wi.dot(hit_record.normal).abs();
+        if cos < 1e-6 {
+            return 0.0;
+        }
+        wi.dot(shading.normal).abs() / cos
+    }
+}
+
+impl Material for Bumped {
+    fn sample(
+        &self,
+        wo: Vec3,
+        hit_record: &HitRecord,
+        rng: &mut dyn RngCore,
+    ) -> Option<BsdfSample> {
+        let shading = self.shading(hit_record);
+        let sample = self.material.sample(wo, &shading, rng)?;
+        Some(BsdfSample {
+            f: sample.f * Self::cos_ratio(sample.wi, &shading, hit_record),
+            ..sample
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Vec3 {
+        let shading = self.shading(hit_record);
+        self.material.eval(wo, wi, &shading) * Self::cos_ratio(wi, &shading, hit_record)
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3, hit_record: &HitRecord) -> Float {
+        self.material.pdf(wo, wi, &self.shading(hit_record))
+    }
+
+    fn emitted(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.material.emitted(u, v, p)
+    }
+
+    fn medium(&self) -> Option<Medium> {
+        self.material.medium()
     }
 }
 
@@ -200,11 +1589,564 @@ impl DiffuseLight {
 }
 
 impl Material for DiffuseLight {
-    fn scatter(&self, _: &Ray, _: &HitRecord, _: &mut Vec3, _: &mut Ray) -> bool {
-        false
+    fn emitted(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.emit.value(u, v, p)
     }
+}
 
-    fn emitted(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
-        self.emit.value(u, v, p)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::SolidTexture;
+
+    /// Number of samples directional albedos are estimated from
+    const SAMPLES: usize = 20000;
+
+    /// Seed of the random numbers materials are sampled with in the estimates, so that every run
+    /// of the tests sees the same samples
+    const SEED: u64 = 1;
+
+    /// Angles to the normal in degrees the outgoing direction is tested at
+    const ANGLES: [Float; 4] = [0.0, 30.0, 60.0, 80.0];
+
+    /// Number of steps along each spherical coordinate BSDFs are integrated over
+    const STEPS: usize = 200;
+
+    /// Share of a density above which a cell of the integration grid gets split up
+    const REFINED_MASS: Float = 1e-4;
+
+    /// Number of steps along each spherical coordinate a split cell is divided into
+    const SUBSTEPS: usize = 4;
+
+    fn gray(value: Float) -> Box<dyn Texture> {
+        SolidTexture::new(Vec3::with_values(value, value, value))
+    }
+
+    /// Returns a hit on the front of the plane z = 0
+    fn hit_record(material: &dyn Material) -> HitRecord<'_> {
+        let ray = Ray::with_values(
+            Vec3::with_values(0.0, 0.0, 1.0),
+            Vec3::with_values(0.0, 0.0, -1.0),
+            None,
+            false,
+        );
+        HitRecord::new(
+            &ray,
+            1.0,
+            Vec3::with_values(0.0, 0.0, 1.0),
+            (0.5, 0.5),
+            material,
+        )
+    }
+
+    /// Returns a hit on the back of the plane z = 0, as seen by a ray travelling inside an object
+    fn hit_record_from_inside(material: &dyn Material) -> HitRecord<'_> {
+        let ray = Ray::with_values(
+            Vec3::with_values(0.0, 0.0, -1.0),
+            Vec3::with_values(0.0, 0.0, 1.0),
+            None,
+            false,
+        );
+        HitRecord::new(
+            &ray,
+            1.0,
+            Vec3::with_values(0.0, 0.0, 1.0),
+            (0.5, 0.5),
+            material,
+        )
+    }
+
+    /// Returns the outgoing direction `theta` degrees away from the normal, off the tangent axes
+    /// so that anisotropic lobes are not seen along a symmetry plane
+    fn outgoing(theta: Float) -> Vec3 {
+        let (sin, cos) = theta.to_radians().sin_cos();
+        Vec3::with_values(0.8 * sin, 0.6 * sin, cos)
+    }
+
+    /// Calls `f` with the midpoint of every cell of a grid in spherical coordinates covering the
+    /// unit sphere, along with the solid angle of the cell. Cells holding more than
+    /// `REFINED_MASS` of `density` are split into a finer grid, so that sharp lobes get resolved
+    /// without refining the whole sphere.
+    fn for_each_cell<D: Fn(Vec3) -> Float, F: FnMut(Vec3, Float)>(density: D, mut f: F) {
+        let d_theta = PI / STEPS as Float;
+        let d_phi = 2.0 * PI / STEPS as Float;
+        let direction = |theta: Float, phi: Float| {
+            let (sin, cos) = theta.sin_cos();
+            Vec3::with_values(sin * phi.cos(), sin * phi.sin(), cos)
+        };
+        for i in 0..STEPS {
+            let theta = (i as Float + 0.5) * d_theta;
+            for j in 0..STEPS {
+                let phi = (j as Float + 0.5) * d_phi;
+                let solid_angle = theta.sin() * d_theta * d_phi;
+                let w = direction(theta, phi);
+                if density(w) * solid_angle <= REFINED_MASS {
+                    f(w, solid_angle);
+                    continue;
+                }
+
+                let (d_theta, d_phi) = (d_theta / SUBSTEPS as Float, d_phi / SUBSTEPS as Float);
+                for k in 0..SUBSTEPS {
+                    let theta = theta + (k as Float + 0.5 - 0.5 * SUBSTEPS as Float) * d_theta;
+                    for l in 0..SUBSTEPS {
+                        let phi = phi + (l as Float + 0.5 - 0.5 * SUBSTEPS as Float) * d_phi;
+                        f(direction(theta, phi), theta.sin() * d_theta * d_phi);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Estimates the fraction of light arriving from all directions that the material reflects
+    /// or transmits towards `wo`
+    fn albedo(material: &dyn Material, wo: Vec3) -> Vec3 {
+        let record = hit_record(material);
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let mut total = Vec3::new();
+        for _ in 0..SAMPLES {
+            if let Some(sample) = material.sample(wo, &record, &mut rng) {
+                if sample.pdf > 0.0 {
+                    total += sample.weight(record.normal);
+                }
+            }
+        }
+        total / SAMPLES as Float
+    }
+
+    /// Integrates `pdf` and `eval` over the sphere and compares them to the fraction of samples
+    /// that are not specular and to their mean weight. The integrals do not depend on `sample`,
+    /// so they catch densities and values that do not describe the directions it returns. Both
+    /// sides of the surface are tested.
+    fn assert_samples_follow_eval_and_pdf(name: &str, material: &dyn Material) {
+        let mut rng = StdRng::seed_from_u64(SEED);
+        for record in [hit_record(material), hit_record_from_inside(material)].iter() {
+            let side = if record.front_face {
+                "outside"
+            } else {
+                "inside"
+            };
+            for &theta in ANGLES.iter() {
+                let wo = outgoing(theta) * record.normal.z();
+
+                let mut sampled = 0;
+                let mut weight = Vec3::new();
+                for _ in 0..SAMPLES {
+                    match material.sample(wo, record, &mut rng) {
+                        Some(sample) if !sample.specular && sample.pdf > 0.0 => {
+                            sampled += 1;
+                            weight += sample.weight(record.normal);
+                        }
+                        _ => {}
+                    }
+                }
+
+                let mut integrated = 0.0;
+                let mut integrated_f = Vec3::new();
+                for_each_cell(
+                    |wi| material.pdf(wo, wi, record),
+                    |wi, solid_angle| {
+                        integrated += material.pdf(wo, wi, record) * solid_angle;
+                        integrated_f += material.eval(wo, wi, record)
+                            * wi.dot(record.normal).abs()
+                            * solid_angle;
+                    },
+                );
+
+                let fraction = sampled as Float / SAMPLES as Float;
+                assert!(
+                    (integrated - fraction).abs() < 0.02,
+                    "{} from the {}: pdf integrates to {} at {} degrees but {} of the samples are \
+                     not specular",
+                    name,
+                    side,
+                    integrated,
+                    theta,
+                    fraction
+                );
+                let weight = weight / SAMPLES as Float;
+                for i in 0..3 {
+                    assert!(
+                        (weight[i] - integrated_f[i]).abs() < 0.02,
+                        "{} from the {}: samples reflect {} at {} degrees but eval integrates to {}",
+                        name,
+                        side,
+                        weight[i],
+                        theta,
+                        integrated_f[i]
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks that the material reflects or transmits all light of a white furnace
+    fn assert_keeps_all_energy(name: &str, material: &dyn Material) {
+        for &theta in ANGLES.iter() {
+            let albedo = albedo(material, outgoing(theta));
+            for i in 0..3 {
+                assert!(
+                    (albedo[i] - 1.0).abs() < 0.01,
+                    "{} reflects {} at {} degrees",
+                    name,
+                    albedo[i],
+                    theta
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lambertian_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf("lambertian", &Lambertian::new(gray(0.7)));
+    }
+
+    #[test]
+    fn rough_diffuse_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf("oren-nayar", &OrenNayar::new(gray(0.7), 30.0));
+        assert_samples_follow_eval_and_pdf("lommel-seeliger", &LommelSeeliger::new(gray(0.7)));
+    }
+
+    #[test]
+    fn oren_nayar_scatters_back_towards_the_viewer() {
+        let smooth = OrenNayar::new(gray(1.0), 0.0);
+        let rough = OrenNayar::new(gray(1.0), 30.0);
+        let lambertian = Lambertian::new(gray(1.0));
+        let hit_record = hit_record(&lambertian);
+
+        let wo = outgoing(60.0);
+        let mirrored = Vec3::with_values(-wo.x(), -wo.y(), wo.z());
+        for &wi in [wo, mirrored, outgoing(30.0)].iter() {
+            let difference =
+                smooth.eval(wo, wi, &hit_record) - lambertian.eval(wo, wi, &hit_record);
+            assert!(difference.len() < 1e-6, "{:?}", difference);
+        }
+        assert!(rough.eval(wo, wo, &hit_record).x() > rough.eval(wo, mirrored, &hit_record).x());
+        assert!(rough.eval(wo, wo, &hit_record).x() > lambertian.eval(wo, wo, &hit_record).x());
+    }
+
+    #[test]
+    fn velvet_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf("velvet", &Velvet::new(gray(0.3), gray(1.0), 0.5));
+    }
+
+    #[test]
+    fn conductor_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf(
+            "isotropic conductor",
+            &Conductor::new(ComplexIor::gold(), 0.5, 0.5),
+        );
+        assert_samples_follow_eval_and_pdf(
+            "anisotropic conductor",
+            &Conductor::new(ComplexIor::copper(), 0.4, 0.8),
+        );
+        assert_samples_follow_eval_and_pdf(
+            "anodized conductor",
+            &Conductor::new(ComplexIor::aluminium(), 0.3, 0.3).thin_film(ThinFilm::new(350.0, 1.6)),
+        );
+    }
+
+    #[test]
+    fn brushed_conductors_follow_the_texture_coordinates() {
+        let material = Conductor::new(ComplexIor::copper(), 0.2, 0.8);
+        let mut along_x = hit_record(&material);
+        along_x.dpdu = Vec3::with_values(2.0, 0.0, 0.5);
+        let mut along_y = along_x;
+        along_y.dpdu = Vec3::with_values(0.0, 2.0, 0.5);
+
+        // NOTE: Turning the texture coordinates a quarter turn about the normal turns the lobe
+        // with them
+        let turn = |w: Vec3| Vec3::with_values(-w.y(), w.x(), w.z());
+        let wo = outgoing(45.0);
+        for &wi in [
+            outgoing(30.0),
+            Vec3::with_values(-0.5, 0.2, 0.8).unit_vector(),
+        ]
+        .iter()
+        {
+            let expected = material.eval(wo, wi, &along_x);
+            let turned = material.eval(turn(wo), turn(wi), &along_y);
+            assert!(
+                (turned - expected).len() < 1e-6 * expected.len(),
+                "{:?}",
+                turned
+            );
+            let pdf = material.pdf(wo, wi, &along_x);
+            assert!((material.pdf(turn(wo), turn(wi), &along_y) - pdf).abs() < 1e-6 * pdf);
+        }
+        let wi = Vec3::with_values(-0.5, 0.2, 0.8).unit_vector();
+        let (first, second) = (
+            material.eval(wo, wi, &along_x),
+            material.eval(wo, wi, &along_y),
+        );
+        assert!(first.x() > 2.0 * second.x(), "{:?} {:?}", first, second);
+    }
+
+    #[test]
+    fn rough_dielectric_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf("rough glass", &RoughDielectric::new(1.5, 0.5));
+        assert_samples_follow_eval_and_pdf("frosted glass", &RoughDielectric::new(1.5, 0.8));
+    }
+
+    #[test]
+    fn rough_dielectric_transmission_follows_generalized_reciprocity() {
+        // NOTE: Transmission carrying radiance satisfies f(wi -> wo) / eta_o^2 =
+        // f(wo -> wi) / eta_i^2, see Veach's thesis 5.2
+        let material = RoughDielectric::new(1.5, 0.5);
+        let outside = Vec3::with_values(0.3, 0.1, 0.9).unit_vector();
+        let inside = Vec3::with_values(-0.2, 0.05, -0.9).unit_vector();
+        let entering = material.eval(outside, inside, &hit_record(&material));
+        let leaving = material.eval(inside, outside, &hit_record_from_inside(&material));
+        assert!(entering.x() > 0.0);
+        assert!(
+            (entering.x() - leaving.x() / (1.5 * 1.5)).abs() < 1e-4,
+            "{} {}",
+            entering.x(),
+            leaving.x()
+        );
+    }
+
+    #[test]
+    fn smooth_dielectrics_scale_transmitted_radiance() {
+        // NOTE: At normal incidence 4% of the light is reflected, the rest is transmitted and
+        // scaled by the squared ratio of the indices of refraction
+        let materials: [(&str, &dyn Material); 2] = [
+            ("dielectric", &Dielectric::new(1.5)),
+            ("smooth rough dielectric", &RoughDielectric::new(1.5, 0.0)),
+        ];
+        for &(name, material) in materials.iter() {
+            let expected = 0.04 + 0.96 / (1.5 * 1.5);
+            let albedo = albedo(material, outgoing(0.0));
+            assert!(
+                (albedo.x() - expected).abs() < 0.01,
+                "{} {}",
+                name,
+                albedo.x()
+            );
+
+            let record = hit_record_from_inside(material);
+            let mut rng = StdRng::seed_from_u64(SEED);
+            let mut total = 0.0;
+            for _ in 0..SAMPLES {
+                let sample = material
+                    .sample(Vec3::with_values(0.0, 0.0, -1.0), &record, &mut rng)
+                    .unwrap();
+                total += sample.weight(record.normal).x();
+            }
+            let expected = 0.04 + 0.96 * 1.5 * 1.5;
+            let albedo = total / SAMPLES as Float;
+            assert!((albedo - expected).abs() < 0.01, "{} {}", name, albedo);
+        }
+    }
+
+    #[test]
+    fn smooth_dielectrics_agree_with_rough_ones_at_zero_roughness() {
+        let smooth = Dielectric::new(1.5);
+        let rough = RoughDielectric::new(1.5, 0.0);
+        for &theta in ANGLES.iter() {
+            let fresnel = fresnel_dielectric(theta.to_radians().cos(), 1.5);
+            let expected = fresnel + (1.0 - fresnel) / (1.5 * 1.5);
+            for &(name, material) in
+                [("dielectric", &smooth as &dyn Material), ("rough", &rough)].iter()
+            {
+                let albedo = albedo(material, outgoing(theta));
+                assert!(
+                    (albedo.x() - expected).abs() < 0.01,
+                    "{} glass reflects and transmits {} at {} degrees instead of {}",
+                    name,
+                    albedo.x(),
+                    theta,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn principled_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf("plastic", &Principled::new(gray(0.7)));
+        assert_samples_follow_eval_and_pdf(
+            "coated metal",
+            &Principled::new(SolidTexture::new(Vec3::with_values(0.9, 0.6, 0.2)))
+                .metallic(gray(0.7))
+                .roughness(gray(0.4))
+                .sheen(gray(1.0))
+                .clearcoat(gray(1.0))
+                .clearcoat_gloss(gray(0.3)),
+        );
+        assert_samples_follow_eval_and_pdf(
+            "frosted glass",
+            &Principled::new(gray(1.0))
+                .roughness(gray(0.6))
+                .transmission(gray(0.8), 1.5),
+        );
+        assert_samples_follow_eval_and_pdf(
+            "coated glass",
+            &Principled::new(gray(1.0))
+                .roughness(gray(0.6))
+                .clearcoat(gray(1.0))
+                .clearcoat_gloss(gray(0.3))
+                .transmission(gray(1.0), 1.5),
+        );
+    }
+
+    #[test]
+    fn principled_clearcoat_is_not_seen_from_inside() {
+        let glass = || {
+            Principled::new(gray(1.0))
+                .roughness(gray(0.3))
+                .transmission(gray(1.0), 1.5)
+        };
+        let coated = glass().clearcoat(gray(1.0));
+        let glass = glass();
+
+        // NOTE: Hits from inside only see the glass, the clearcoat on the outside must not
+        // reflect light back into the object
+        let wo = -outgoing(30.0);
+        let wi = Vec3::with_values(0.4, -0.3, -0.8).unit_vector();
+        let coated_f = coated.eval(wo, wi, &hit_record_from_inside(&coated));
+        let glass_f = glass.eval(wo, wi, &hit_record_from_inside(&glass));
+        assert!(glass_f.x() > 0.0);
+        assert!(
+            (coated_f.x() - glass_f.x()).abs() < 1e-6,
+            "{} {}",
+            coated_f.x(),
+            glass_f.x()
+        );
+    }
+
+    #[test]
+    fn mix_samples_follow_eval_and_pdf() {
+        assert_samples_follow_eval_and_pdf(
+            "rusty metal",
+            &Mix::new(
+                Box::new(Conductor::new(ComplexIor::silver(), 0.4, 0.4)),
+                Box::new(Lambertian::new(gray(0.5))),
+                gray(0.3),
+            ),
+        );
+    }
+
+    #[test]
+    fn thin_films_keep_all_energy_of_dielectrics() {
+        assert_keeps_all_energy(
+            "soap bubble",
+            &Dielectric::new(1.0).thin_film(ThinFilm::new(400.0, 1.33)),
+        );
+
+        // NOTE: Light entering the glass is scaled by the squared ratio of the indices of
+        // refraction, everything the film does not reflect is transmitted
+        let film = ThinFilm::new(300.0, 1.38);
+        let material = Dielectric::new(1.5).thin_film(film);
+        for &theta in ANGLES.iter() {
+            let albedo = albedo(&material, outgoing(theta));
+            let reflectance =
+                film.reflectance(theta.to_radians().cos(), 1.0, |_| Complex::real(1.5), None);
+            for i in 0..3 {
+                let expected = reflectance[i] + (1.0 - reflectance[i]) / (1.5 * 1.5);
+                assert!(
+                    (albedo[i] - expected).abs() < 0.01,
+                    "coated glass reflects and transmits {} at {} degrees instead of {}",
+                    albedo[i],
+                    theta,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn flat_maps_keep_the_geometric_normal() {
+        let material = Bumped::new(Box::new(Lambertian::new(gray(0.5))))
+            .normal_map(SolidTexture::new(Vec3::with_values(0.5, 0.5, 1.0)))
+            .bump_map(gray(0.3), 0.1);
+        for record in [hit_record(&material), hit_record_from_inside(&material)].iter() {
+            let shading = material.shading(record);
+            assert!(
+                (shading.normal - record.normal).len() < 1e-5,
+                "{:?}",
+                shading.normal
+            );
+        }
+    }
+
+    #[test]
+    fn normal_maps_tilt_the_normal_towards_the_tangent() {
+        let material = Bumped::new(Box::new(Lambertian::new(gray(0.5))))
+            .normal_map(SolidTexture::new(Vec3::with_values(1.0, 0.5, 1.0)));
+        let record = hit_record(&material);
+        let shading = material.shading(&record);
+        let expected = (record.dpdu.unit_vector() + record.normal).unit_vector();
+        assert!(
+            (shading.normal - expected).len() < 1e-5,
+            "{:?}",
+            shading.normal
+        );
+
+        assert_samples_follow_eval_and_pdf("normal mapped lambertian", &material);
+    }
+
+    #[test]
+    fn coat_over_black_reflects_its_fresnel_reflectance() {
+        let material = Coated::new(Box::new(Lambertian::new(gray(0.0))), 1.5, 0.0);
+        for &theta in ANGLES.iter() {
+            let albedo = albedo(&material, outgoing(theta));
+            let fresnel = fresnel_dielectric(theta.to_radians().cos(), 1.5);
+            assert!(
+                (albedo.x() - fresnel).abs() < 0.01,
+                "coated black reflects {} at {} degrees instead of {}",
+                albedo.x(),
+                theta,
+                fresnel
+            );
+        }
+    }
+
+    #[test]
+    fn smooth_conductor_reflects_its_fresnel_reflectance() {
+        let ior = ComplexIor::gold();
+        let material = Conductor::new(ior, 0.0, 0.0);
+        for &theta in ANGLES.iter() {
+            let albedo = albedo(&material, outgoing(theta));
+            let fresnel = ior.fresnel(theta.to_radians().cos());
+            for i in 0..3 {
+                assert!(
+                    (albedo[i] - fresnel[i]).abs() < 1e-3,
+                    "smooth gold reflects {} at {} degrees instead of {}",
+                    albedo[i],
+                    theta,
+                    fresnel[i]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn conductor_fresnel_matches_the_dielectric_limit() {
+        // NOTE: Without absorption the conductor Fresnel term is the one of a dielectric, whose
+        // reflectance at normal incidence is ((eta - 1) / (eta + 1))^2
+        let reflectance = fresnel_conductor(1.0, 1.5, 0.0);
+        assert!((reflectance - 0.04).abs() < 1e-4, "{}", reflectance);
+        assert!((fresnel_conductor(0.0, 1.5, 0.0) - 1.0).abs() < 1e-4);
+
+        let gold = ComplexIor::gold().fresnel(1.0);
+        assert!(gold.r() > gold.g() && gold.g() > gold.b(), "{:?}", gold);
+    }
+
+    #[test]
+    fn lossless_materials_keep_all_energy_in_a_white_furnace() {
+        assert_keeps_all_energy("lambertian", &Lambertian::new(gray(1.0)));
+        assert_keeps_all_energy("metal", &Metal::new(gray(1.0), 0.0));
+        assert_keeps_all_energy(
+            "coated lambertian",
+            &Coated::new(Box::new(Lambertian::new(gray(1.0))), 1.5, 0.0),
+        );
+        assert_keeps_all_energy(
+            "mix",
+            &Mix::new(
+                Box::new(Lambertian::new(gray(1.0))),
+                Box::new(Metal::new(gray(1.0), 0.0)),
+                gray(0.5),
+            ),
+        );
     }
 }
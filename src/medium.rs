@@ -0,0 +1,363 @@
+use crate::vec3::{Float, Onb, Vec3, PI};
+use rand::Rng;
+
+/// Maximum number of nested media a ray can be inside of at once, entering further media is
+/// ignored
+pub const MAX_NESTED_MEDIA: usize = 8;
+
+/// Wavelength in nanometers of the sodium D line, at which indices of refraction are usually
+/// quoted
+pub const LAMBDA_D: Float = 589.3;
+
+/// Enum describes how the index of refraction of a medium changes with the wavelength of light.
+/// Wavelengths are given in micrometers for both models, as most published coefficients are.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dispersion {
+    /// Cauchy's equation, n = a + b / lambda^2
+    Cauchy { a: Float, b: Float },
+    /// Sellmeier equation, n^2 = 1 + sum(b_i lambda^2 / (lambda^2 - c_i))
+    Sellmeier { b: [Float; 3], c: [Float; 3] },
+}
+
+impl Dispersion {
+    /// Returns the index of refraction at `lambda` nanometers
+    pub fn ior(&self, lambda: Float) -> Float {
+        let lambda = lambda / 1000.0;
+        let lambda2 = lambda * lambda;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / lambda2,
+            Dispersion::Sellmeier { b, c } => (1.0
+                + b.iter()
+                    .zip(c.iter())
+                    .map(|(b, c)| b * lambda2 / (lambda2 - c))
+                    .sum::<Float>())
+            .sqrt(),
+        }
+    }
+
+    /// Returns the Sellmeier coefficients of Schott N-BK7, the most common optical glass
+    // NOTE: Coefficients are kept as published, they get rounded when the `f32` feature is enabled
+    #[allow(clippy::excessive_precision)]
+    pub fn bk7() -> Self {
+        Dispersion::Sellmeier {
+            b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+            c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+        }
+    }
+
+    /// Returns the Sellmeier coefficients of diamond
+    pub fn diamond() -> Self {
+        Dispersion::Sellmeier {
+            b: [0.3306, 4.3356, 0.0],
+            c: [0.030_625, 0.011_236, 0.0],
+        }
+    }
+}
+
+/// Struct describes the homogeneous medium filling the inside of a dielectric object. Media that
+/// scatter light are traced with a random walk, which is how subsurface scattering is rendered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Medium {
+    /// Index of refraction of the medium, at the D line for dispersive media
+    pub ior: Float,
+    /// Variation of the index of refraction with wavelength, `None` for media that do not split
+    /// light into colors
+    pub dispersion: Option<Dispersion>,
+    /// Absorption coefficient per unit of distance for every channel
+    pub absorption: Vec3,
+    /// Scattering coefficient per unit of distance for every channel
+    pub scattering: Vec3,
+    /// Mean cosine of the angle light gets deflected by when scattered, from -1 for light thrown
+    /// back to 1 for light carrying on forward, 0 scattering equally in all directions
+    pub anisotropy: Float,
+    /// Where objects overlap, the medium with the highest priority fills the shared volume and
+    /// the surfaces of the other objects inside of it are ignored
+    pub priority: u32,
+}
+
+impl Medium {
+    /// Creates a clear medium that does not absorb any light
+    pub fn new(ior: Float) -> Self {
+        Self {
+            ior,
+            dispersion: None,
+            absorption: Vec3::new(),
+            scattering: Vec3::new(),
+            anisotropy: 0.0,
+            priority: 0,
+        }
+    }
+
+    /// Creates a medium that scatters light below the surface of an object. The object ends up
+    /// with the color `albedo` once light bounced around inside of it, and `radius` is the mean
+    /// distance light travels in the medium between two events, per channel.
+    pub fn subsurface(ior: Float, albedo: Vec3, radius: Vec3) -> Self {
+        let mut medium = Self::new(ior);
+        for i in 0..3 {
+            // NOTE: Inverts the albedo left after multiple scattering into the single scattering
+            // albedo, using the fit of Kulla and Conty "Revisiting Physically Based Shading at
+            // Imageworks"
+            let a = albedo[i].clamp(0.0, 1.0);
+            let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+            let single_albedo = 1.0 - s * s;
+            let extinction = 1.0 / radius[i].max(1e-6);
+            medium.scattering[i] = single_albedo * extinction;
+            medium.absorption[i] = extinction - medium.scattering[i];
+        }
+        medium
+    }
+
+    /// Creates a clear medium whose index of refraction follows `dispersion`
+    pub fn dispersive(dispersion: Dispersion) -> Self {
+        Self {
+            dispersion: Some(dispersion),
+            ..Self::new(dispersion.ior(LAMBDA_D))
+        }
+    }
+
+    /// Returns the index of refraction for light of `wavelength` nanometers, paths that were not
+    /// split into wavelengths see the index at the D line
+    pub fn ior_at(&self, wavelength: Option<Float>) -> Float {
+        match (self.dispersion, wavelength) {
+            (Some(dispersion), Some(lambda)) => dispersion.ior(lambda),
+            _ => self.ior,
+        }
+    }
+
+    /// Returns the absorption coefficient that leaves `color` of the light after travelling
+    /// `distance` through the medium
+    pub fn absorption_from_color(color: Vec3, distance: Float) -> Vec3 {
+        let mut absorption = Vec3::new();
+        for i in 0..3 {
+            absorption[i] = -color[i].clamp(1e-6, 1.0).ln() / distance;
+        }
+        absorption
+    }
+
+    /// Returns the rate per unit of distance at which light gets either absorbed or scattered
+    pub fn extinction(&self) -> Vec3 {
+        self.absorption + self.scattering
+    }
+
+    /// Whether light gets scattered inside the medium rather than only absorbed
+    pub fn scatters(&self) -> bool {
+        self.scattering.x() > 0.0 || self.scattering.y() > 0.0 || self.scattering.z() > 0.0
+    }
+
+    /// Returns the fraction of light left after travelling `distance` through the medium without
+    /// getting absorbed or scattered, following the Beer-Lambert law
+    pub fn transmittance(&self, distance: Float) -> Vec3 {
+        let extinction = self.extinction();
+        let mut transmittance = Vec3::new();
+        for i in 0..3 {
+            transmittance[i] = (-extinction[i] * distance).exp();
+        }
+        transmittance
+    }
+
+    /// Samples the direction light travelling along `direction` continues in after being
+    /// scattered, following the Henyey-Greenstein phase function. The direction is sampled
+    /// exactly proportional to the phase function, so scattering needs no further weighting.
+    pub fn sample_phase(&self, direction: Vec3) -> Vec3 {
+        let mut rng = rand::thread_rng();
+        let u1 = rng.gen::<Float>();
+        let u2 = rng.gen::<Float>();
+
+        let g = self.anisotropy.clamp(-0.99, 0.99);
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u1
+        } else {
+            let s = (1.0 - g * g) / (1.0 + g - 2.0 * g * u1);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        Onb::from_w(direction.unit_vector()).local(Vec3::with_values(
+            sin * phi.cos(),
+            sin * phi.sin(),
+            cos,
+        ))
+    }
+}
+
+/// Struct holds the media a ray is currently inside of, in the order it entered them. It is a
+/// fixed size array so that rays stay cheap to spawn.
+#[derive(Clone, Copy, Debug)]
+pub struct MediumStack {
+    media: [Option<Medium>; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl MediumStack {
+    pub fn new() -> Self {
+        Self {
+            media: [None; MAX_NESTED_MEDIA],
+            len: 0,
+        }
+    }
+
+    /// Returns the medium the ray is travelling through, the one with the highest priority or the
+    /// most recently entered one among equals. `None` stands for vacuum.
+    pub fn current(&self) -> Option<Medium> {
+        self.dominant(None)
+    }
+
+    /// Returns the medium on the other side of the surface of `medium`, the one that is
+    /// travelled through while outside of it
+    pub fn surrounding(&self, medium: &Medium) -> Option<Medium> {
+        let skip = self.media[..self.len]
+            .iter()
+            .rposition(|entry| entry.as_ref() == Some(medium));
+        self.dominant(skip)
+    }
+
+    fn dominant(&self, skip: Option<usize>) -> Option<Medium> {
+        self.media[..self.len]
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| Some(i) != skip)
+            .filter_map(|(_, entry)| *entry)
+            .fold(None, |dominant: Option<Medium>, medium| match dominant {
+                Some(dominant) if dominant.priority > medium.priority => Some(dominant),
+                _ => Some(medium),
+            })
+    }
+
+    /// Returns whether the surface of `medium` is an actual interface. Surfaces of media with a
+    /// lower priority than the one surrounding them are overridden and rays pass straight through.
+    pub fn is_interface(&self, medium: &Medium) -> bool {
+        match self.surrounding(medium) {
+            Some(surrounding) => medium.priority >= surrounding.priority,
+            None => true,
+        }
+    }
+
+    /// Returns the stack after crossing the surface of `medium`, entering it if `entering` is set
+    /// and leaving it otherwise
+    pub fn crossed(&self, medium: Medium, entering: bool) -> Self {
+        let mut stack = *self;
+        if entering {
+            if stack.len < MAX_NESTED_MEDIA {
+                stack.media[stack.len] = Some(medium);
+                stack.len += 1;
+            }
+        } else if let Some(i) = stack.media[..stack.len]
+            .iter()
+            .rposition(|entry| *entry == Some(medium))
+        {
+            stack.media.copy_within(i + 1..stack.len, i);
+            stack.len -= 1;
+            stack.media[stack.len] = None;
+        }
+        stack
+    }
+}
+
+impl Default for MediumStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn medium(ior: Float, priority: u32) -> Medium {
+        Medium {
+            priority,
+            ..Medium::new(ior)
+        }
+    }
+
+    #[test]
+    fn dispersion_matches_published_indices() {
+        let bk7 = Medium::dispersive(Dispersion::bk7());
+        assert!((bk7.ior - 1.5168).abs() < 1e-4, "{}", bk7.ior);
+        assert!((bk7.ior_at(Some(486.1)) - 1.5224).abs() < 1e-4);
+        assert!((bk7.ior_at(Some(656.3)) - 1.5143).abs() < 1e-4);
+        assert_eq!(bk7.ior_at(None), bk7.ior);
+
+        let diamond = Medium::dispersive(Dispersion::diamond());
+        assert!((diamond.ior - 2.417).abs() < 2e-3, "{}", diamond.ior);
+
+        let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.004 };
+        assert!(cauchy.ior(400.0) > cauchy.ior(700.0));
+    }
+
+    #[test]
+    fn transmission_color_is_left_after_the_given_distance() {
+        let color = Vec3::with_values(0.2, 0.5, 1.0);
+        let medium = Medium {
+            absorption: Medium::absorption_from_color(color, 2.0),
+            ..Medium::new(1.5)
+        };
+        let transmittance = medium.transmittance(2.0);
+        for i in 0..3 {
+            assert!(
+                (transmittance[i] - color[i]).abs() < 1e-5,
+                "{:?}",
+                transmittance
+            );
+        }
+    }
+
+    #[test]
+    fn subsurface_media_keep_their_radius_and_albedo() {
+        let radius = Vec3::with_values(1.0, 0.5, 0.25);
+        let white = Medium::subsurface(1.3, Vec3::with_values(1.0, 1.0, 1.0), radius);
+        let black = Medium::subsurface(1.3, Vec3::new(), radius);
+        for i in 0..3 {
+            assert!((white.extinction()[i] * radius[i] - 1.0).abs() < 1e-4);
+            assert!(white.absorption[i].abs() < 1e-3 * white.scattering[i]);
+            assert!(black.scattering[i].abs() < 1e-3 * black.absorption[i]);
+        }
+        assert!(white.scatters());
+        assert!(!Medium::new(1.3).scatters());
+    }
+
+    #[test]
+    fn phase_samples_are_deflected_by_the_anisotropy_on_average() {
+        let direction = Vec3::with_values(0.0, 0.6, 0.8);
+        for &g in [-0.7, 0.0, 0.3, 0.9].iter() {
+            let medium = Medium {
+                anisotropy: g,
+                ..Medium::new(1.0)
+            };
+            let samples = 100000;
+            let mut mean = 0.0;
+            for _ in 0..samples {
+                mean += medium.sample_phase(direction).dot(direction) / samples as Float;
+            }
+            assert!((mean - g).abs() < 0.01, "{} instead of {}", mean, g);
+        }
+    }
+
+    #[test]
+    fn surfaces_inside_higher_priority_media_are_ignored() {
+        let glass = medium(1.5, 3);
+        let water = medium(1.33, 1);
+        let ice = medium(1.31, 2);
+
+        // NOTE: A ray enters the glass, then the water overlapping its wall, then the ice
+        let stack = MediumStack::new().crossed(glass, true);
+        assert!(!stack.is_interface(&water));
+        let stack = stack.crossed(water, true);
+        assert_eq!(stack.current(), Some(glass));
+
+        // NOTE: Leaving the glass wall uncovers the water, whose index the glass surface sees
+        assert!(stack.is_interface(&glass));
+        assert_eq!(stack.surrounding(&glass), Some(water));
+        let stack = stack.crossed(glass, false);
+        assert_eq!(stack.current(), Some(water));
+
+        assert!(stack.is_interface(&ice));
+        assert_eq!(stack.surrounding(&ice), Some(water));
+        let stack = stack.crossed(ice, true);
+        assert_eq!(stack.current(), Some(ice));
+        assert_eq!(stack.surrounding(&ice), Some(water));
+
+        let stack = stack.crossed(ice, false).crossed(water, false);
+        assert_eq!(stack.current(), None);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::vec3::{Float, Vec3, PI};
+use rand::{Rng, RngCore};
+
+/// Roughness below which a microfacet lobe is treated as a perfectly smooth surface. The lobe gets
+/// too sharp to be sampled and evaluated reliably well before that point.
+pub const SMOOTH_ALPHA: Float = 1e-3;
+
+/// Function maps a perceptual roughness in [0, 1] to the alpha parameter of a microfacet
+/// distribution. Squaring it makes roughness look roughly linear to the eye.
+pub fn roughness_to_alpha(roughness: Float) -> Float {
+    let roughness = roughness.clamp(0.0, 1.0);
+    roughness * roughness
+}
+
+/// Struct describes the GGX, or Trowbridge-Reitz, distribution of microfacet normals. All
+/// directions are given in the local shading frame where the surface normal is the z axis,
+/// `alpha_x` and `alpha_y` being the roughness along the tangent and the bitangent.
+#[derive(Clone, Copy, Debug)]
+pub struct TrowbridgeReitz {
+    alpha_x: Float,
+    alpha_y: Float,
+}
+
+impl TrowbridgeReitz {
+    pub fn new(alpha_x: Float, alpha_y: Float) -> Self {
+        Self {
+            alpha_x: alpha_x.max(1e-4),
+            alpha_y: alpha_y.max(1e-4),
+        }
+    }
+
+    /// Whether the distribution is sharp enough to be treated as a perfect mirror
+    pub fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ALPHA
+    }
+
+    /// Returns the density of microfacets facing `wh` per unit of macro surface area
+    pub fn d(&self, wh: Vec3) -> Float {
+        if wh.z() <= 0.0 {
+            return 0.0;
+        }
+
+        let x = wh.x() / self.alpha_x;
+        let y = wh.y() / self.alpha_y;
+        let e = x * x + y * y + wh.z() * wh.z();
+        1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+    }
+
+    /// Returns the Smith auxiliary function, the area of microfacets hidden from `w` per unit of
+    /// visible microfacet area
+    pub fn lambda(&self, w: Vec3) -> Float {
+        let cos2 = w.z() * w.z();
+        if cos2 == 0.0 {
+            return Float::INFINITY;
+        }
+
+        let alpha2_tan2 = (self.alpha_x * self.alpha_x * w.x() * w.x()
+            + self.alpha_y * self.alpha_y * w.y() * w.y())
+            / cos2;
+        ((1.0 + alpha2_tan2).sqrt() - 1.0) / 2.0
+    }
+
+    /// Returns the fraction of microfacets visible from `w`
+    pub fn g1(&self, w: Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Returns the fraction of microfacets visible from both `wo` and `wi`, using the height
+    /// correlated form of the Smith masking-shadowing function
+    pub fn g(&self, wo: Vec3, wi: Vec3) -> Float {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Samples a microfacet normal visible from `wo`, which has to lie in the upper hemisphere.
+    /// Uses Heitz's "Sampling the GGX Distribution of Visible Normals", so that no sample is
+    /// wasted on facets facing away from the viewer.
+    pub fn sample_wh(&self, wo: Vec3, rng: &mut dyn RngCore) -> Vec3 {
+        let u1 = rng.gen::<Float>();
+        let u2 = rng.gen::<Float>();
+
+        // NOTE: Stretch the view direction into the configuration where the distribution is the
+        // hemisphere of radius one
+        let vh =
+            Vec3::with_values(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()).unit_vector();
+        let len2 = vh.x() * vh.x() + vh.y() * vh.y();
+        let t1 = if len2 > 0.0 {
+            Vec3::with_values(-vh.y(), vh.x(), 0.0) / len2.sqrt()
+        } else {
+            Vec3::with_values(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(t1);
+
+        let r = u1.sqrt();
+        let phi = 2.0 * PI * u2;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + vh.z());
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+        Vec3::with_values(
+            self.alpha_x * nh.x(),
+            self.alpha_y * nh.y(),
+            nh.z().max(0.0),
+        )
+        .unit_vector()
+    }
+
+    /// Returns the density `sample_wh` picks the microfacet normal `wh` with, seen from `wo`
+    pub fn pdf(&self, wo: Vec3, wh: Vec3) -> Float {
+        if wo.z() <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(wh).max(0.0) * self.d(wh) / wo.z()
+    }
+}
+
+/// Struct describes the GTR1, or Berry, distribution of microfacet normals that Disney's
+/// principled BSDF uses for its clearcoat. Its long tails give coatings a soft haze around the
+/// highlight.
+#[derive(Clone, Copy, Debug)]
+pub struct Gtr1 {
+    alpha: Float,
+}
+
+impl Gtr1 {
+    pub fn new(alpha: Float) -> Self {
+        Self {
+            alpha: alpha.clamp(1e-3, 0.999),
+        }
+    }
+
+    /// Returns the density of microfacets facing `wh` per unit of macro surface area
+    pub fn d(&self, wh: Vec3) -> Float {
+        if wh.z() <= 0.0 {
+            return 0.0;
+        }
+
+        let alpha2 = self.alpha * self.alpha;
+        let t = 1.0 + (alpha2 - 1.0) * wh.z() * wh.z();
+        (alpha2 - 1.0) / (PI * alpha2.ln() * t)
+    }
+
+    /// Samples a microfacet normal proportional to `d(wh) * wh.z()`
+    pub fn sample_wh(&self, rng: &mut dyn RngCore) -> Vec3 {
+        let u1 = rng.gen::<Float>();
+        let u2 = rng.gen::<Float>();
+
+        let alpha2 = self.alpha * self.alpha;
+        let cos2 = (1.0 - alpha2.powf(1.0 - u1)) / (1.0 - alpha2);
+        let cos = cos2.clamp(0.0, 1.0).sqrt();
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * u2;
+        Vec3::with_values(sin * phi.cos(), sin * phi.sin(), cos)
+    }
+
+    /// Returns the density `sample_wh` picks the microfacet normal `wh` with
+    pub fn pdf(&self, wh: Vec3) -> Float {
+        self.d(wh) * wh.z().max(0.0)
+    }
+}
+
+/// Returns the uncorrelated Smith masking function of a GGX distribution with roughness `alpha`,
+/// as used for the fixed roughness of the clearcoat
+pub fn smith_g1(w: Vec3, alpha: Float) -> Float {
+    TrowbridgeReitz::new(alpha, alpha).g1(w)
+}
@@ -0,0 +1,425 @@
+use crate::texture::Texture;
+use crate::vec3::{Float, Vec3};
+use noise::{NoiseFn, Perlin, Seedable};
+
+/// Struct maps values in [0, 1] to colors, interpolating linearly between stops sorted by their
+/// position. Values before the first or after the last stop take the color of that stop.
+#[derive(Clone, Debug)]
+pub struct ColorRamp {
+    stops: Vec<(Float, Vec3)>,
+}
+
+impl ColorRamp {
+    pub fn new(mut stops: Vec<(Float, Vec3)>) -> Self {
+        assert!(!stops.is_empty(), "Color ramps need at least one stop");
+        if let Some(&(position, _)) = stops.iter().find(|(position, _)| !position.is_finite()) {
+            panic!("Color ramp stops need finite positions, got {}", position);
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Self { stops }
+    }
+
+    /// Returns the ramp going from black to white
+    pub fn grayscale() -> Self {
+        Self::new(vec![
+            (0.0, Vec3::new()),
+            (1.0, Vec3::with_values(1.0, 1.0, 1.0)),
+        ])
+    }
+
+    pub fn at(&self, t: Float) -> Vec3 {
+        let upper = self.stops.iter().position(|&(position, _)| position > t);
+        match upper {
+            Some(0) => self.stops[0].1,
+            None => self.stops[self.stops.len() - 1].1,
+            Some(i) => {
+                let (a, color_a) = self.stops[i - 1];
+                let (b, color_b) = self.stops[i];
+                let s = (t - a) / (b - a);
+                (1.0 - s) * color_a + s * color_b
+            }
+        }
+    }
+}
+
+/// Struct sums octaves of seeded Perlin noise, each octave `lacunarity` times the frequency and
+/// `gain` times the amplitude of the one before
+#[derive(Clone, Debug)]
+pub struct Fractal {
+    perlin: Perlin,
+    octaves: u32,
+    lacunarity: Float,
+    gain: Float,
+}
+
+impl Fractal {
+    pub fn new(seed: u32, octaves: u32, lacunarity: Float, gain: Float) -> Self {
+        Self {
+            perlin: Perlin::new().set_seed(seed),
+            octaves: octaves.max(1),
+            lacunarity,
+            gain,
+        }
+    }
+
+    // NOTE: noise only samples in double precision, the conversion is a no-op unless the `f32`
+    // feature is enabled.
+    #[allow(clippy::useless_conversion)]
+    fn perlin(&self, p: Vec3) -> Float {
+        self.perlin
+            .get([f64::from(p.x()), f64::from(p.y()), f64::from(p.z())]) as Float
+    }
+
+    /// Returns fractal Brownian motion at `p`, roughly in [-1, 1]
+    pub fn fbm(&self, p: Vec3) -> Float {
+        self.sum(p, |noise| noise)
+    }
+
+    /// Returns ridged noise at `p` in [0, 1], which folds every octave into sharp crests where
+    /// the noise crosses zero
+    pub fn ridged(&self, p: Vec3) -> Float {
+        self.sum(p, |noise| {
+            let ridge = 1.0 - noise.abs().min(1.0);
+            ridge * ridge
+        })
+    }
+
+    /// Sums `octave` of the noise over all octaves, normalized by the total amplitude
+    fn sum<F: Fn(Float) -> Float>(&self, p: Vec3, octave: F) -> Float {
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut amplitudes = 0.0;
+        let mut frequency = 1.0;
+        for _ in 0..self.octaves {
+            total += amplitude * octave(self.perlin(frequency * p));
+            amplitudes += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        total / amplitudes
+    }
+}
+
+/// FbmTexture shows fractal noise in shades of gray, either plain fBm or ridged noise
+#[derive(Clone, Debug)]
+pub struct FbmTexture {
+    fractal: Fractal,
+    scale: Float,
+    ridged: bool,
+}
+
+impl FbmTexture {
+    pub fn new(fractal: Fractal, scale: Float, ridged: bool) -> Box<Self> {
+        Box::new(Self {
+            fractal,
+            scale,
+            ridged,
+        })
+    }
+}
+
+impl Texture for FbmTexture {
+    fn value(&self, _: Float, _: Float, p: Vec3) -> Vec3 {
+        let p = self.scale * p;
+        let value = if self.ridged {
+            self.fractal.ridged(p)
+        } else {
+            0.5 * (1.0 + self.fractal.fbm(p))
+        };
+        let value = value.clamp(0.0, 1.0);
+        Vec3::with_values(value, value, value)
+    }
+}
+
+/// Enum selects what a Worley texture shows of the cells around the feature points scattered
+/// through space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorleyFeature {
+    /// Distance to the closest feature point, dark at the points and bright between them
+    Distance,
+    /// Difference between the distances to the closest two feature points, dark along the edges
+    /// between cells
+    Edges,
+    /// Random shade for every cell, like a stained glass window
+    Cells,
+}
+
+/// Function hashes the integer coordinates of a cell, see "Hash Functions for GPU Rendering"
+/// by Jarzynski and Olano
+fn hash_cell(seed: u32, x: i32, y: i32, z: i32) -> u32 {
+    let mut h = seed
+        .wrapping_mul(0x9E37_79B9)
+        .wrapping_add((x as u32).wrapping_mul(0x8DA6_B343))
+        .wrapping_add((y as u32).wrapping_mul(0xD816_3841))
+        .wrapping_add((z as u32).wrapping_mul(0xCB1A_B31F));
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7FEB_352D);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846C_A68B);
+    h ^ (h >> 16)
+}
+
+/// Function turns a hash into a number in [0, 1)
+fn hash_to_unit(hash: u32) -> Float {
+    (hash >> 8) as Float / (1u32 << 24) as Float
+}
+
+/// WorleyTexture shows cellular noise, placing one feature point at a random position inside
+/// every unit cell of space
+#[derive(Clone, Debug)]
+pub struct WorleyTexture {
+    seed: u32,
+    scale: Float,
+    feature: WorleyFeature,
+}
+
+impl WorleyTexture {
+    pub fn new(seed: u32, scale: Float, feature: WorleyFeature) -> Box<Self> {
+        Box::new(Self {
+            seed,
+            scale,
+            feature,
+        })
+    }
+
+    /// Returns the distances to the closest two feature points around `p` and the hash of the
+    /// cell of the closest one
+    fn closest(&self, p: Vec3) -> (Float, Float, u32) {
+        let cell = (
+            p.x().floor() as i32,
+            p.y().floor() as i32,
+            p.z().floor() as i32,
+        );
+        let mut closest = (Float::INFINITY, Float::INFINITY, 0);
+        for dz in -1..=1 {
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (x, y, z) = (cell.0 + dx, cell.1 + dy, cell.2 + dz);
+                    let hash = hash_cell(self.seed, x, y, z);
+                    let point = Vec3::with_values(
+                        x as Float + hash_to_unit(hash),
+                        y as Float + hash_to_unit(hash_cell(hash, 1, 0, 0)),
+                        z as Float + hash_to_unit(hash_cell(hash, 0, 1, 0)),
+                    );
+                    let distance = (point - p).len();
+                    if distance < closest.0 {
+                        closest = (distance, closest.0, hash);
+                    } else if distance < closest.1 {
+                        closest.1 = distance;
+                    }
+                }
+            }
+        }
+        closest
+    }
+}
+
+impl Texture for WorleyTexture {
+    fn value(&self, _: Float, _: Float, p: Vec3) -> Vec3 {
+        let (first, second, hash) = self.closest(self.scale * p);
+        let value = match self.feature {
+            WorleyFeature::Distance => first,
+            WorleyFeature::Edges => second - first,
+            WorleyFeature::Cells => hash_to_unit(hash_cell(hash, 0, 0, 1)),
+        };
+        let value = value.clamp(0.0, 1.0);
+        Vec3::with_values(value, value, value)
+    }
+}
+
+/// WoodTexture shows the growth rings of a trunk running along the y axis, bent by fractal noise
+/// so that they are not perfect circles
+#[derive(Clone, Debug)]
+pub struct WoodTexture {
+    fractal: Fractal,
+    scale: Float,
+    distortion: Float,
+    light: Vec3,
+    dark: Vec3,
+}
+
+impl WoodTexture {
+    pub fn new(
+        fractal: Fractal,
+        scale: Float,
+        distortion: Float,
+        light: Vec3,
+        dark: Vec3,
+    ) -> Box<Self> {
+        Box::new(Self {
+            fractal,
+            scale,
+            distortion,
+            light,
+            dark,
+        })
+    }
+}
+
+impl Texture for WoodTexture {
+    fn value(&self, _: Float, _: Float, p: Vec3) -> Vec3 {
+        let p = self.scale * p;
+        let radius = (p.x() * p.x() + p.z() * p.z()).sqrt() + self.distortion * self.fractal.fbm(p);
+        // NOTE: Rings grow slowly into the late wood and end abruptly where the next year starts
+        let ring = radius - radius.floor();
+        let t = ring * ring * ring;
+        (1.0 - t) * self.light + t * self.dark
+    }
+}
+
+/// MarbleTexture shows veins running along the x axis, turbulent noise pushing them around
+/// before the result is looked up in a color ramp
+#[derive(Clone, Debug)]
+pub struct MarbleTexture {
+    fractal: Fractal,
+    scale: Float,
+    turbulence: Float,
+    ramp: ColorRamp,
+}
+
+impl MarbleTexture {
+    pub fn new(fractal: Fractal, scale: Float, turbulence: Float, ramp: ColorRamp) -> Box<Self> {
+        Box::new(Self {
+            fractal,
+            scale,
+            turbulence,
+            ramp,
+        })
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn value(&self, _: Float, _: Float, p: Vec3) -> Vec3 {
+        let p = self.scale * p;
+        let turbulence = self.fractal.sum(p, Float::abs);
+        self.ramp
+            .at(0.5 * (1.0 + (p.x() + self.turbulence * turbulence).sin()))
+    }
+}
+
+/// Enum selects the coordinate a gradient texture runs along
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GradientAxis {
+    U,
+    V,
+    X,
+    Y,
+    Z,
+}
+
+/// GradientTexture looks a coordinate of the lookup up in a color ramp, after scaling and
+/// offsetting it
+#[derive(Clone, Debug)]
+pub struct GradientTexture {
+    axis: GradientAxis,
+    scale: Float,
+    offset: Float,
+    ramp: ColorRamp,
+}
+
+impl GradientTexture {
+    pub fn new(axis: GradientAxis, scale: Float, offset: Float, ramp: ColorRamp) -> Box<Self> {
+        Box::new(Self {
+            axis,
+            scale,
+            offset,
+            ramp,
+        })
+    }
+}
+
+impl Texture for GradientTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        let coordinate = match self.axis {
+            GradientAxis::U => u,
+            GradientAxis::V => v,
+            GradientAxis::X => p.x(),
+            GradientAxis::Y => p.y(),
+            GradientAxis::Z => p.z(),
+        };
+        self.ramp.at(self.scale * coordinate + self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> impl Iterator<Item = Vec3> {
+        (0..200).map(|i| {
+            let i = i as Float;
+            Vec3::with_values(0.37 * i, 0.11 * i - 3.0, 1.3 * (0.5 * i).sin())
+        })
+    }
+
+    #[test]
+    fn color_ramps_interpolate_between_stops() {
+        let red = Vec3::with_values(1.0, 0.0, 0.0);
+        let blue = Vec3::with_values(0.0, 0.0, 1.0);
+        let ramp = ColorRamp::new(vec![(0.75, blue), (0.25, red)]);
+        assert_eq!(ramp.at(0.0), red);
+        assert_eq!(ramp.at(1.0), blue);
+        assert_eq!(ramp.at(0.5), 0.5 * red + 0.5 * blue);
+    }
+
+    #[test]
+    #[should_panic(expected = "finite positions")]
+    fn color_ramps_reject_stops_without_a_position() {
+        ColorRamp::new(vec![(0.0, Vec3::new()), (Float::NAN, Vec3::new())]);
+    }
+
+    #[test]
+    fn seeds_change_the_noise_and_nothing_else() {
+        let first = FbmTexture::new(Fractal::new(1, 5, 2.0, 0.5), 1.5, false);
+        let again = FbmTexture::new(Fractal::new(1, 5, 2.0, 0.5), 1.5, false);
+        let second = FbmTexture::new(Fractal::new(2, 5, 2.0, 0.5), 1.5, false);
+        let mut differs = false;
+        for p in points() {
+            let value = first.value(0.0, 0.0, p);
+            assert_eq!(value, again.value(0.0, 0.0, p));
+            differs |= value != second.value(0.0, 0.0, p);
+        }
+        assert!(differs);
+    }
+
+    #[test]
+    fn noise_stays_in_the_unit_range() {
+        let textures: Vec<Box<dyn Texture>> = vec![
+            FbmTexture::new(Fractal::new(3, 6, 2.0, 0.5), 2.0, false),
+            FbmTexture::new(Fractal::new(3, 6, 2.0, 0.5), 2.0, true),
+            WorleyTexture::new(3, 2.0, WorleyFeature::Distance),
+            WorleyTexture::new(3, 2.0, WorleyFeature::Edges),
+            WorleyTexture::new(3, 2.0, WorleyFeature::Cells),
+        ];
+        for texture in textures.iter() {
+            let values: Vec<Float> = points().map(|p| texture.scalar(0.0, 0.0, p)).collect();
+            assert!(values.iter().all(|&value| (0.0..=1.0).contains(&value)));
+            // NOTE: The noise must vary instead of sitting at a clamped end of the range
+            let mean = values.iter().sum::<Float>() / values.len() as Float;
+            assert!(mean > 0.05 && mean < 0.95, "{:?} {}", texture, mean);
+        }
+    }
+
+    #[test]
+    fn worley_distances_vanish_at_feature_points() {
+        let worley = WorleyTexture::new(7, 1.0, WorleyFeature::Distance);
+        let hash = hash_cell(7, 2, -1, 0);
+        let point = Vec3::with_values(
+            2.0 + hash_to_unit(hash),
+            -1.0 + hash_to_unit(hash_cell(hash, 1, 0, 0)),
+            hash_to_unit(hash_cell(hash, 0, 1, 0)),
+        );
+        assert!(worley.scalar(0.0, 0.0, point) < 1e-6);
+        assert!(worley.scalar(0.0, 0.0, point + Vec3::with_values(0.05, 0.0, 0.0)) > 0.0);
+    }
+
+    #[test]
+    fn gradients_follow_their_axis() {
+        let gradient = GradientTexture::new(GradientAxis::Y, 0.5, 0.5, ColorRamp::grayscale());
+        let at = |y: Float| gradient.scalar(0.0, 0.0, Vec3::with_values(3.0, y, -2.0));
+        assert!((at(-1.0) - 0.0).abs() < 1e-6);
+        assert!((at(0.0) - 0.5).abs() < 1e-6);
+        assert!((at(1.0) - 1.0).abs() < 1e-6);
+        assert!((at(5.0) - 1.0).abs() < 1e-6);
+    }
+}
@@ -1,17 +1,80 @@
-use crate::hitable::{HitRecord, Hitable, HitableList};
-use crate::vec3::Vec3;
-use rand::Rng;
+use crate::hitable::{HitRecord, Hitable};
+use crate::medium::{Medium, MediumStack};
+use crate::spectrum::{sample_wavelength, wavelength_weight};
+use crate::vec3::{next_float_down, next_float_up, Float, Vec3, PI};
+use rand::{Rng, RngCore};
 
-// A, B, time
-pub struct Ray(Vec3, Vec3, f64, pub bool);
+/// Function offsets the origin `p` of a ray leaving a surface in direction `w` along the geometric
+/// normal `n`, just far enough to leave the box of floating point error `p_error` around the hit
+/// point. Unlike a fixed epsilon this works the same no matter the scale of the scene, see PBR
+/// 3.9.5.
+pub fn offset_ray_origin(p: Vec3, p_error: Vec3, n: Vec3, w: Vec3) -> Vec3 {
+    let d = n.abs().dot(p_error);
+    let mut offset = d * n;
+    if w.dot(n) < 0.0 {
+        offset = -offset;
+    }
+
+    let mut po = p + offset;
+    for i in 0..3 {
+        if offset[i] > 0.0 {
+            po[i] = next_float_up(po[i]);
+        } else if offset[i] < 0.0 {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+    po
+}
+
+/// Maximum number of times a path gets scattered on a random walk through a medium, paths still
+/// inside after that are considered absorbed
+const MAX_SCATTERING_EVENTS: usize = 1024;
+
+/// Struct describes the rays through the neighbouring pixels to the right of and above the pixel
+/// a camera ray was traced through. Where they hit a surface tells how much of a texture the
+/// pixel covers there, see PBR 10.1.
+#[derive(Clone, Copy, Debug)]
+pub struct RayDifferential {
+    pub rx_origin: Vec3,
+    pub rx_direction: Vec3,
+    pub ry_origin: Vec3,
+    pub ry_direction: Vec3,
+}
+
+// A, B, time, debug, media the ray travels through, wavelength, differentials
+pub struct Ray(
+    Vec3,
+    Vec3,
+    Float,
+    pub bool,
+    MediumStack,
+    Option<Float>,
+    Option<RayDifferential>,
+);
 
 impl Ray {
     pub fn new(debug: bool) -> Self {
-        Self(Vec3::new(), Vec3::new(), 0.0, debug)
+        Self(
+            Vec3::new(),
+            Vec3::new(),
+            0.0,
+            debug,
+            MediumStack::new(),
+            None,
+            None,
+        )
     }
 
-    pub fn with_values(a: Vec3, b: Vec3, t: Option<f64>, debug: bool) -> Self {
-        Self(a, b, t.unwrap_or(0.0), debug)
+    pub fn with_values(a: Vec3, b: Vec3, t: Option<Float>, debug: bool) -> Self {
+        Self(
+            a,
+            b,
+            t.unwrap_or(0.0),
+            debug,
+            MediumStack::new(),
+            None,
+            None,
+        )
     }
 
     pub fn origin(&self) -> Vec3 {
@@ -22,7 +85,7 @@ impl Ray {
         self.1
     }
 
-    pub fn point_at_param(&self, t: f64) -> Vec3 {
+    pub fn point_at_param(&self, t: Float) -> Vec3 {
         self.0 + t * self.1
     }
 
@@ -31,14 +94,48 @@ impl Ray {
         self.1 = rhs.direction();
     }
 
-    pub fn time(&self) -> f64 {
+    pub fn time(&self) -> Float {
         self.2
     }
 
-    pub fn random_in_sphere() -> Vec3 {
-        let mut rn = rand::thread_rng();
+    /// Returns whether the ray is traced in debug lighting, where missed rays see white
+    pub fn debug(&self) -> bool {
+        self.3
+    }
+
+    /// Returns the media the ray is inside of
+    pub fn media(&self) -> &MediumStack {
+        &self.4
+    }
+
+    pub fn set_media(&mut self, media: MediumStack) {
+        self.4 = media;
+    }
+
+    /// Returns the wavelength in nanometers the path of the ray is traced at, `None` until the
+    /// path gets split up by a dispersive medium
+    pub fn wavelength(&self) -> Option<Float> {
+        self.5
+    }
+
+    pub fn set_wavelength(&mut self, wavelength: Option<Float>) {
+        self.5 = wavelength;
+    }
+
+    /// Returns the rays through the neighbouring pixels, `None` once the path scattered off
+    /// anything but a specular surface
+    pub fn differential(&self) -> Option<RayDifferential> {
+        self.6
+    }
+
+    pub fn set_differential(&mut self, differential: Option<RayDifferential>) {
+        self.6 = differential;
+    }
+
+    pub fn random_in_sphere(rng: &mut dyn RngCore) -> Vec3 {
         loop {
-            let p = 2.0 * Vec3::with_values(rn.gen::<f64>(), rn.gen::<f64>(), rn.gen::<f64>())
+            let p = 2.0
+                * Vec3::with_values(rng.gen::<Float>(), rng.gen::<Float>(), rng.gen::<Float>())
                 - Vec3::with_values(1.0, 1.0, 1.0);
             if p.squared_len() < 1.0 {
                 return p;
@@ -46,10 +143,25 @@ impl Ray {
         }
     }
 
+    /// Returns a direction on the unit hemisphere around the z axis, distributed proportional to
+    /// the cosine of its angle to the axis. Uses Malley's method, projecting a uniform point on the
+    /// unit disk up onto the hemisphere, so it never needs to reject samples.
+    pub fn random_cosine_direction(rng: &mut dyn RngCore) -> Vec3 {
+        let r1 = rng.gen::<Float>();
+        let r2 = rng.gen::<Float>();
+        let phi = 2.0 * PI * r1;
+
+        Vec3::with_values(
+            phi.cos() * r2.sqrt(),
+            phi.sin() * r2.sqrt(),
+            (1.0 - r2).sqrt(),
+        )
+    }
+
     pub fn random_in_unit_disk() -> Vec3 {
         let mut rn = rand::thread_rng();
         loop {
-            let p = 2.0 * Vec3::with_values(rn.gen::<f64>(), rn.gen::<f64>(), 0.0)
+            let p = 2.0 * Vec3::with_values(rn.gen::<Float>(), rn.gen::<Float>(), 0.0)
                 - Vec3::with_values(1.0, 1.0, 0.0);
             if p.squared_len() < 1.0 {
                 return p;
@@ -57,25 +169,153 @@ impl Ray {
         }
     }
 
-    pub fn color(&self, world: &mut HitableList, depth: i64) -> Vec3 {
-        let mut record = HitRecord::new();
+    /// Returns whether the surface of `medium`, or the medium the ray travels through, changes
+    /// the index of refraction with wavelength
+    fn is_dispersive_at(&self, medium: Option<Medium>) -> bool {
+        medium
+            .into_iter()
+            .chain(self.media().current())
+            .any(|medium| medium.dispersion.is_some())
+    }
+
+    pub fn color(&self, world: &dyn Hitable, depth: i64) -> Vec3 {
+        let record = world.hit(self, 0.0, Float::MAX);
+        match self.media().current() {
+            Some(medium) if medium.scatters() => self.walk(world, medium, record, depth),
+            medium => {
+                // NOTE: Light gets absorbed by the medium the ray travelled through to reach the
+                // hit
+                let transmittance = match (medium, &record) {
+                    (Some(medium), Some(record)) => {
+                        medium.transmittance(record.t * self.direction().len())
+                    }
+                    _ => Vec3::with_values(1.0, 1.0, 1.0),
+                };
+                transmittance * self.shade(world, record, depth)
+            }
+        }
+    }
+
+    /// Follows the ray on a random walk through the scattering `medium` it is inside of, until
+    /// it reaches the surface `record` of the object it is in. Distances are sampled with the
+    /// extinction of a single channel, picked in proportion to the throughput left in it, and
+    /// weighted by the density of all channels combined. Channels that were mostly absorbed stop
+    /// steering the walk, which keeps media scattering colors differently from getting noisy.
+    fn walk<'a>(
+        &self,
+        world: &'a dyn Hitable,
+        medium: Medium,
+        mut record: Option<HitRecord<'a>>,
+        depth: i64,
+    ) -> Vec3 {
+        let mut rng = rand::thread_rng();
+        let extinction = medium.extinction();
+        // NOTE: Scattering inside of the medium spreads the footprint of the pixel out completely, so
+        // the walk drops the differentials
+        let mut ray = Ray(self.0, self.1, self.2, self.3, self.4, self.5, None);
+        let mut throughput = Vec3::with_values(1.0, 1.0, 1.0);
+
+        for _ in 0..MAX_SCATTERING_EVENTS {
+            let len = ray.direction().len();
+            let distance = record.as_ref().map_or(Float::INFINITY, |r| r.t * len);
+            let total = throughput.x() + throughput.y() + throughput.z();
+            if total <= 0.0 {
+                return Vec3::new();
+            }
+            let probabilities = throughput / total;
+            let u = rng.gen::<Float>();
+            let channel = if u < probabilities.x() {
+                0
+            } else if u < probabilities.x() + probabilities.y() {
+                1
+            } else {
+                2
+            };
+            let t = if extinction[channel] > 0.0 {
+                -(1.0 - rng.gen::<Float>()).ln() / extinction[channel]
+            } else {
+                Float::INFINITY
+            };
+
+            if t >= distance {
+                let transmittance = medium.transmittance(distance);
+                throughput *= transmittance / probabilities.dot(transmittance);
+                return throughput * ray.shade(world, record, depth);
+            }
+
+            let transmittance = medium.transmittance(t);
+            throughput *=
+                medium.scattering * transmittance / probabilities.dot(extinction * transmittance);
+            ray = Ray(
+                ray.point_at_param(t / len),
+                medium.sample_phase(ray.direction()),
+                ray.2,
+                ray.3,
+                ray.4,
+                ray.5,
+                None,
+            );
+            record = world.hit(&ray, 0.0, Float::MAX);
+        }
+        Vec3::new()
+    }
+
+    /// Returns the light leaving the surface `record` towards the origin of the ray, or the
+    /// background when the ray hit nothing
+    fn shade(&self, world: &dyn Hitable, record: Option<HitRecord>, depth: i64) -> Vec3 {
+        if let Some(mut record) = record {
+            record.compute_differentials(self);
+
+            // NOTE: Paths are split into single wavelengths when they first meet a dispersive
+            // medium, weighting the throughput by the color of the wavelength that was picked
+            let medium = record.material.medium();
+            let mut weight = Vec3::with_values(1.0, 1.0, 1.0);
+            let mut wavelength = self.wavelength();
+            if wavelength.is_none() && self.is_dispersive_at(medium) {
+                let lambda = sample_wavelength();
+                weight = wavelength_weight(lambda);
+                wavelength = Some(lambda);
+            }
+            record.wavelength = wavelength;
+
+            if let Some(medium) = medium {
+                // NOTE: Surfaces overridden by a medium with a higher priority are not there for
+                // the ray, it only enters or leaves their medium and carries on
+                if !self.media().is_interface(&medium) {
+                    if depth >= 50 {
+                        return Vec3::new();
+                    }
+                    let mut next = record.spawn_specular_ray(self, self.direction());
+                    next.set_media(self.media().crossed(medium, record.front_face));
+                    next.set_wavelength(wavelength);
+                    return weight * next.color(world, depth + 1);
+                }
+                record.surrounding_ior = self
+                    .media()
+                    .surrounding(&medium)
+                    .map_or(1.0, |m| m.ior_at(wavelength));
+            }
 
-        let hit = world.hit(self, 0.001, std::f64::MAX, &mut record);
-        if hit.0 {
-            let mut scattered = Ray::new(self.3);
+            let mut scattered = Ray::new(self.debug());
             let mut attenuation = Vec3::new();
-            let emitted = hit.1.emitted(record.u, record.v, record.p);
+            let emitted = record.material.emitted(record.u, record.v, record.p);
             if depth < 50
-                && hit
-                    .1
+                && record
+                    .material
                     .scatter(self, &record, &mut attenuation, &mut scattered)
             {
+                if let Some(medium) = medium {
+                    if scattered.direction().dot(record.normal) < 0.0 {
+                        scattered.set_media(self.media().crossed(medium, record.front_face));
+                    }
+                }
+                scattered.set_wavelength(wavelength);
                 let color = scattered.color(world, depth + 1);
-                return emitted + (attenuation * color);
+                return emitted + (weight * attenuation * color);
             }
             return emitted;
         }
-        if self.3 {
+        if self.debug() {
             Vec3::with_values(1.0, 1.0, 1.0)
         } else {
             Vec3::new()
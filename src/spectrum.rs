@@ -0,0 +1,93 @@
+use crate::vec3::{Float, Vec3};
+use rand::Rng;
+
+/// Shortest wavelength in nanometers that paths are traced at
+pub const LAMBDA_MIN: Float = 380.0;
+/// Longest wavelength in nanometers that paths are traced at
+pub const LAMBDA_MAX: Float = 780.0;
+
+/// Integral of `xyz_to_rgb(cie_xyz(lambda))` over the visible range, per channel
+#[allow(clippy::excessive_precision)]
+const RGB_INTEGRAL: (Float, Float, Float) = (128.361023, 101.538081, 97.064801);
+
+/// Piecewise gaussian with a different width on each side of its mean
+fn gaussian(lambda: Float, mu: Float, sigma_below: Float, sigma_above: Float) -> Float {
+    let sigma = if lambda < mu {
+        sigma_below
+    } else {
+        sigma_above
+    };
+    let t = (lambda - mu) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+/// Returns the CIE 1931 color matching functions at `lambda` nanometers, using the multi-lobe fit
+/// of Wyman et al. "Simple Analytic Approximations to the CIE XYZ Color Matching Functions"
+pub fn cie_xyz(lambda: Float) -> Vec3 {
+    Vec3::with_values(
+        1.056 * gaussian(lambda, 599.8, 37.9, 31.0) + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+            - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2),
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1),
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8),
+    )
+}
+
+/// Converts a CIE XYZ color to linear sRGB
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+    Vec3::with_values(
+        3.240_454_2 * xyz.x() - 1.537_138_5 * xyz.y() - 0.498_531_4 * xyz.z(),
+        -0.969_266 * xyz.x() + 1.876_010_8 * xyz.y() + 0.041_556 * xyz.z(),
+        0.055_643_4 * xyz.x() - 0.204_025_9 * xyz.y() + 1.057_225_2 * xyz.z(),
+    )
+}
+
+/// Samples a wavelength in nanometers uniformly over the visible range
+pub fn sample_wavelength() -> Float {
+    LAMBDA_MIN + rand::thread_rng().gen::<Float>() * (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+/// Returns the factor an RGB path throughput gets multiplied by when the path continues at the
+/// single wavelength `lambda` picked by `sample_wavelength`. The factors are normalized so that
+/// they average to white, which keeps the color of light that is not split up unchanged.
+pub fn wavelength_weight(lambda: Float) -> Vec3 {
+    let rgb = xyz_to_rgb(cie_xyz(lambda)) * (LAMBDA_MAX - LAMBDA_MIN);
+    Vec3::with_values(
+        rgb.x() / RGB_INTEGRAL.0,
+        rgb.y() / RGB_INTEGRAL.1,
+        rgb.z() / RGB_INTEGRAL.2,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wavelength_weights_average_to_white() {
+        let steps = 4000;
+        let mut total = Vec3::new();
+        for i in 0..steps {
+            let lambda =
+                LAMBDA_MIN + (i as Float + 0.5) * (LAMBDA_MAX - LAMBDA_MIN) / steps as Float;
+            total += wavelength_weight(lambda);
+        }
+        let average = total / steps as Float;
+        for i in 0..3 {
+            assert!((average[i] - 1.0).abs() < 1e-4, "{:?}", average);
+        }
+    }
+
+    #[test]
+    fn wavelengths_map_to_their_hue() {
+        let red = wavelength_weight(650.0);
+        assert!(red.r() > red.g() && red.r() > red.b(), "{:?}", red);
+        let green = wavelength_weight(530.0);
+        assert!(
+            green.g() > green.r() && green.g() > green.b(),
+            "{:?}",
+            green
+        );
+        let blue = wavelength_weight(450.0);
+        assert!(blue.b() > blue.r() && blue.b() > blue.g(), "{:?}", blue);
+    }
+}
@@ -1,12 +1,42 @@
-use crate::vec3::Vec3;
+use crate::color::ColorSpace;
+use crate::vec3::{Float, Vec3};
 use dyn_clone::DynClone;
-use image::{DynamicImage, GenericImageView, Pixel};
+use image::hdr::HDRDecoder;
+use image::DynamicImage;
 use noise::{NoiseFn, Perlin, Turbulence};
 use std::fmt;
 use std::fmt::Debug as DebugTrait;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Struct describes the area of texture space a pixel covers around a lookup, as the change of
+/// the texture coordinates from one pixel to the next along both axes of the image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Footprint {
+    pub dudx: Float,
+    pub dvdx: Float,
+    pub dudy: Float,
+    pub dvdy: Float,
+}
 
 pub trait Texture: Sync + DynClone + Send + DebugTrait {
-    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3;
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3;
+
+    /// Returns the texture averaged over the `footprint` of a pixel, which keeps detail smaller
+    /// than a pixel from aliasing. Textures that can not be filtered return their value at the
+    /// center of the footprint.
+    fn filtered(&self, u: Float, v: Float, p: Vec3, _footprint: &Footprint) -> Vec3 {
+        self.value(u, v, p)
+    }
+
+    /// Returns the texture as a single value, such as a roughness or a mask, averaging the
+    /// channels of colored textures
+    fn scalar(&self, u: Float, v: Float, p: Vec3) -> Float {
+        let value = self.value(u, v, p);
+        (value.x() + value.y() + value.z()) / 3.0
+    }
 }
 
 dyn_clone::clone_trait_object!(Texture);
@@ -23,7 +53,7 @@ impl SolidTexture {
 }
 
 impl Texture for SolidTexture {
-    fn value(&self, _: f64, _: f64, _: Vec3) -> Vec3 {
+    fn value(&self, _: Float, _: Float, _: Vec3) -> Vec3 {
         self.color
     }
 }
@@ -40,25 +70,36 @@ impl CheckerTexture {
     }
 }
 
-impl Texture for CheckerTexture {
-    fn value(&self, u: f64, v: f64, p: Vec3) -> Vec3 {
+impl CheckerTexture {
+    /// Returns the texture of the square of the checkerboard `p` lies in
+    fn square(&self, p: Vec3) -> &dyn Texture {
         let sines = (10.0 * p.x()).sin() * (10.0 * p.y()).sin() * (10.0 * p.z()).sin();
         if sines < 0.0 {
-            self.odd.value(u, v, p)
+            self.odd.as_ref()
         } else {
-            self.even.value(u, v, p)
+            self.even.as_ref()
         }
     }
 }
 
+impl Texture for CheckerTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.square(p).value(u, v, p)
+    }
+
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        self.square(p).filtered(u, v, p, footprint)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct NoiseTexture {
     noise: noise::Turbulence<Perlin>,
-    scale: f64,
+    scale: Float,
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f64) -> Box<Self> {
+    pub fn new(scale: Float) -> Box<Self> {
         Box::new(Self {
             noise: Turbulence::new(Perlin::new())
                 .set_frequency(1.5)
@@ -67,52 +108,540 @@ impl NoiseTexture {
         })
     }
 
-    pub fn turbulence(&self, p: Vec3) -> f64 {
-        self.noise.get([p.x(), p.y(), p.z()])
+    // NOTE: noise only samples in double precision, the conversion is a no-op unless the `f32`
+    // feature is enabled.
+    #[allow(clippy::useless_conversion)]
+    pub fn turbulence(&self, p: Vec3) -> Float {
+        self.noise
+            .get([f64::from(p.x()), f64::from(p.y()), f64::from(p.z())]) as Float
     }
 }
 
 impl Texture for NoiseTexture {
-    fn value(&self, _: f64, _: f64, p: Vec3) -> Vec3 {
+    fn value(&self, _: Float, _: Float, p: Vec3) -> Vec3 {
         Vec3::with_values(1.0, 1.0, 1.0)
             * 0.5
             * (1.0 + (self.scale * p.z() + 10.0 * self.turbulence(p)).sin())
     }
 }
 
-#[derive(Clone)]
-pub struct ImageTexture {
+/// Enum selects how image textures blend the texels around a lookup
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Takes the closest texel, which looks blocky up close
+    Nearest,
+    /// Blends the four closest texels linearly
+    Bilinear,
+    /// Blends the sixteen closest texels with Catmull-Rom splines, which stays sharper than
+    /// bilinear filtering
+    Bicubic,
+}
+
+/// Enum selects what image textures show outside of the unit square of texture coordinates
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+    /// Tiles the image
+    Repeat,
+    /// Tiles the image, flipping every other tile so that their edges match
+    Mirror,
+    /// Stretches the texels along the edges of the image
+    Clamp,
+}
+
+impl Wrap {
+    /// Returns the texel index that `i` maps to in a row or column of `n` texels
+    fn apply(self, i: i64, n: i64) -> usize {
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {
+                    i
+                } else {
+                    2 * n - 1 - i
+                }
+            }
+            Wrap::Clamp => i.clamp(0, n - 1),
+        };
+        i as usize
+    }
+}
+
+/// Struct transforms texture coordinates before an image is looked up. Coordinates are scaled,
+/// then rotated counterclockwise by `rotation` degrees and finally offset, so a scale of four
+/// tiles an image four times across a surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UvTransform {
+    pub scale: (Float, Float),
+    pub offset: (Float, Float),
+    pub rotation: Float,
+}
+
+impl UvTransform {
+    pub fn new(scale: (Float, Float), offset: (Float, Float), rotation: Float) -> Self {
+        Self {
+            scale,
+            offset,
+            rotation,
+        }
+    }
+
+    /// Returns the transform that leaves coordinates unchanged
+    pub fn identity() -> Self {
+        Self::new((1.0, 1.0), (0.0, 0.0), 0.0)
+    }
+
+    pub fn apply(&self, u: Float, v: Float) -> (Float, Float) {
+        let (u, v) = self.apply_vector(u, v);
+        (u + self.offset.0, v + self.offset.1)
+    }
+
+    /// Transforms a difference between texture coordinates, which the offset does not change
+    fn apply_vector(&self, u: Float, v: Float) -> (Float, Float) {
+        let (u, v) = (u * self.scale.0, v * self.scale.1);
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        (cos * u - sin * v, sin * u + cos * v)
+    }
+
+    /// Transforms the footprint of a lookup along with its coordinates
+    pub fn apply_footprint(&self, footprint: &Footprint) -> Footprint {
+        let (dudx, dvdx) = self.apply_vector(footprint.dudx, footprint.dvdx);
+        let (dudy, dvdy) = self.apply_vector(footprint.dudy, footprint.dvdy);
+        Footprint {
+            dudx,
+            dvdx,
+            dudy,
+            dvdy,
+        }
+    }
+}
+
+/// Returns the Catmull-Rom weights of the four texels around a lookup `t` of the way between the
+/// middle two
+fn catmull_rom_weights(t: Float) -> [Float; 4] {
+    [
+        ((2.0 - t) * t - 1.0) * t / 2.0,
+        ((3.0 * t - 5.0) * t * t + 2.0) / 2.0,
+        ((4.0 - 3.0 * t) * t + 1.0) * t / 2.0,
+        (t - 1.0) * t * t / 2.0,
+    ]
+}
+
+/// Struct holds one level of the MIP pyramid of an image, every level halving the resolution of
+/// the one before
+struct MipLevel {
     nx: u32,
     ny: u32,
-    image: DynamicImage,
+    texels: Vec<Vec3>,
+}
+
+impl MipLevel {
+    /// Returns the next level of the pyramid, averaging blocks of two by two texels
+    fn downsample(&self) -> Self {
+        let nx = (self.nx / 2).max(1);
+        let ny = (self.ny / 2).max(1);
+        let mut texels = Vec::with_capacity((nx * ny) as usize);
+        for j in 0..ny {
+            for i in 0..nx {
+                let mut color = Vec3::new();
+                for &y in [2 * j, 2 * j + 1].iter() {
+                    for &x in [2 * i, 2 * i + 1].iter() {
+                        let (x, y) = (x.min(self.nx - 1), y.min(self.ny - 1));
+                        color += self.texels[(y * self.nx + x) as usize];
+                    }
+                }
+                texels.push(color / 4.0);
+            }
+        }
+        Self { nx, ny, texels }
+    }
+}
+
+/// ImageTexture maps an image over the texture coordinates, the top row of the image being at
+/// `v = 1`. Texels are decoded once when loading, along with a MIP pyramid of the image, and
+/// shared between clones of the texture.
+#[derive(Clone)]
+pub struct ImageTexture {
+    levels: Arc<Vec<MipLevel>>,
+    filter: Filter,
+    wrap: Wrap,
+    transform: UvTransform,
 }
 
 impl ImageTexture {
+    /// Loads an image looked up without filtering and clamped at its edges
     pub fn new(path: &str) -> Box<Self> {
-        let image = image::open(path).unwrap();
+        Self::with_options(
+            path,
+            Filter::Nearest,
+            Wrap::Clamp,
+            UvTransform::identity(),
+            None,
+        )
+    }
+
+    /// Loads an image stored in `color_space`, which defaults to linear light for Radiance and
+    /// OpenEXR images and to sRGB for all other formats
+    pub fn with_options(
+        path: &str,
+        filter: Filter,
+        wrap: Wrap,
+        transform: UvTransform,
+        color_space: Option<ColorSpace>,
+    ) -> Box<Self> {
+        // NOTE: Radiance and OpenEXR images hold linear values that may go above one, they are
+        // kept as they are instead of going through the eight bit channels of other formats
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        let ((nx, ny, texels), default) = match extension.as_deref() {
+            Some("hdr") => (Self::load_hdr(path), ColorSpace::Linear),
+            Some("exr") => (Self::load_exr(path), ColorSpace::Linear),
+            _ => (
+                Self::decode_image(&image::open(path).unwrap()),
+                ColorSpace::Srgb,
+            ),
+        };
+        let color_space = color_space.unwrap_or(default);
+        let texels = texels
+            .into_iter()
+            .map(|texel| color_space.to_linear(texel))
+            .collect();
+        Self::from_texels(nx, ny, texels, filter, wrap, transform)
+    }
+
+    /// Reads the texels of a Radiance RGBE image
+    #[allow(clippy::useless_conversion)]
+    fn load_hdr(path: &str) -> (u32, u32, Vec<Vec3>) {
+        let decoder = HDRDecoder::new(BufReader::new(File::open(path).unwrap())).unwrap();
+        let metadata = decoder.metadata();
+        let texels = decoder
+            .read_image_hdr()
+            .unwrap()
+            .into_iter()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                Vec3::with_values(Float::from(r), Float::from(g), Float::from(b))
+            })
+            .collect();
+        (metadata.width, metadata.height, texels)
+    }
+
+    /// Reads the texels of the first layer with color channels of an OpenEXR image, half and
+    /// single precision channels alike
+    #[allow(clippy::useless_conversion)]
+    fn load_exr(path: &str) -> (u32, u32, Vec<Vec3>) {
+        let image = exr::prelude::read_first_rgba_layer_from_file(
+            path,
+            |resolution, _| {
+                let (nx, ny) = (resolution.width(), resolution.height());
+                (nx, ny, vec![Vec3::new(); nx * ny])
+            },
+            |(nx, _, texels), position, (r, g, b, _): (f32, f32, f32, f32)| {
+                texels[position.y() * *nx + position.x()] =
+                    Vec3::with_values(Float::from(r), Float::from(g), Float::from(b));
+            },
+        )
+        .unwrap();
+        let (nx, ny, texels) = image.layer_data.channel_data.pixels;
+        (nx as u32, ny as u32, texels)
+    }
+
+    pub fn from_image(
+        image: &DynamicImage,
+        filter: Filter,
+        wrap: Wrap,
+        transform: UvTransform,
+        color_space: ColorSpace,
+    ) -> Box<Self> {
+        let (nx, ny, texels) = Self::decode_image(image);
+        let texels = texels
+            .into_iter()
+            .map(|texel| color_space.to_linear(texel))
+            .collect();
+        Self::from_texels(nx, ny, texels, filter, wrap, transform)
+    }
+
+    /// Reads the eight bit channels of an image as they are stored
+    fn decode_image(image: &DynamicImage) -> (u32, u32, Vec<Vec3>) {
+        let image = image.to_rgb();
         let (nx, ny) = image.dimensions();
-        Box::new(Self { nx, ny, image })
+        let texels = image
+            .pixels()
+            .map(|pixel| {
+                let [r, g, b] = pixel.0;
+                Vec3::with_values(r as Float / 255.0, g as Float / 255.0, b as Float / 255.0)
+            })
+            .collect();
+        (nx, ny, texels)
+    }
+
+    /// Creates a texture from rows of linear texels, starting with the top row of the image
+    fn from_texels(
+        nx: u32,
+        ny: u32,
+        texels: Vec<Vec3>,
+        filter: Filter,
+        wrap: Wrap,
+        transform: UvTransform,
+    ) -> Box<Self> {
+        let mut levels = vec![MipLevel { nx, ny, texels }];
+        loop {
+            let last = levels.last().unwrap();
+            if last.nx == 1 && last.ny == 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
+        }
+        Box::new(Self {
+            levels: Arc::new(levels),
+            filter,
+            wrap,
+            transform,
+        })
+    }
+
+    /// Returns the texel of `level` in column `i` and row `j` counted from the top, wrapping
+    /// indices that fall outside of the image
+    fn texel(&self, level: &MipLevel, i: i64, j: i64) -> Vec3 {
+        let i = self.wrap.apply(i, level.nx as i64);
+        let j = self.wrap.apply(j, level.ny as i64);
+        level.texels[j * level.nx as usize + i]
+    }
+
+    /// Looks up the transformed coordinates `u` and `v` in a single level of the pyramid
+    fn lookup(&self, level: usize, u: Float, v: Float) -> Vec3 {
+        let level = &self.levels[level];
+        // NOTE: Texel centers sit at half integer coordinates
+        let x = u * level.nx as Float;
+        let y = (1.0 - v) * level.ny as Float;
+
+        match self.filter {
+            Filter::Nearest => self.texel(level, x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (i, j) = (x.floor(), y.floor());
+                let (tx, ty) = (x - i, y - j);
+                let (i, j) = (i as i64, j as i64);
+                (1.0 - ty)
+                    * ((1.0 - tx) * self.texel(level, i, j) + tx * self.texel(level, i + 1, j))
+                    + ty * ((1.0 - tx) * self.texel(level, i, j + 1)
+                        + tx * self.texel(level, i + 1, j + 1))
+            }
+            Filter::Bicubic => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (i, j) = (x.floor(), y.floor());
+                let wx = catmull_rom_weights(x - i);
+                let wy = catmull_rom_weights(y - j);
+                let (i, j) = (i as i64, j as i64);
+
+                let mut color = Vec3::new();
+                for (dy, wy) in wy.iter().enumerate() {
+                    for (dx, wx) in wx.iter().enumerate() {
+                        color += wx * wy * self.texel(level, i + dx as i64 - 1, j + dy as i64 - 1);
+                    }
+                }
+                // NOTE: The splines overshoot around sharp edges, which must not give negative
+                // colors
+                Vec3::with_values(color.x().max(0.0), color.y().max(0.0), color.z().max(0.0))
+            }
+        }
     }
 }
 
 impl fmt::Debug for ImageTexture {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "ImageBuffer {{ nx: {}, ny: {} }}", self.nx, self.ny)
+        write!(
+            f,
+            "ImageTexture {{ nx: {}, ny: {}, levels: {}, filter: {:?}, wrap: {:?}, transform: {:?} }}",
+            self.levels[0].nx,
+            self.levels[0].ny,
+            self.levels.len(),
+            self.filter,
+            self.wrap,
+            self.transform
+        )
     }
 }
 
 impl Texture for ImageTexture {
-    fn value(&self, u: f64, v: f64, _: Vec3) -> Vec3 {
-        let mut i = (u * self.nx as f64) as u32;
-        let mut j = ((1.0 - v) * self.ny as f64 - 0.001) as u32;
-        if i > self.nx - 1 {
-            i = self.nx - 1;
+    fn value(&self, u: Float, v: Float, _: Vec3) -> Vec3 {
+        let (u, v) = self.transform.apply(u, v);
+        self.lookup(0, u, v)
+    }
+
+    /// Picks the two levels of the pyramid whose texels are closest in size to the footprint
+    /// and blends between them, so that distant surfaces show the average of the texels a pixel
+    /// covers instead of a random one of them
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        let footprint = self.transform.apply_footprint(footprint);
+        let (nx, ny) = (self.levels[0].nx as Float, self.levels[0].ny as Float);
+        let width = (footprint.dudx.abs() * nx)
+            .max(footprint.dudy.abs() * nx)
+            .max(footprint.dvdx.abs() * ny)
+            .max(footprint.dvdy.abs() * ny);
+        // NOTE: NaN widths from degenerate footprints fall back to the unfiltered lookup too
+        if width.is_nan() || width <= 1.0 {
+            return self.value(u, v, p);
+        }
+
+        let (u, v) = self.transform.apply(u, v);
+        let level = width.log2().min((self.levels.len() - 1) as Float);
+        let lower = level.floor() as usize;
+        let t = level - lower as Float;
+        if lower + 1 >= self.levels.len() {
+            return self.lookup(lower, u, v);
+        }
+        (1.0 - t) * self.lookup(lower, u, v) + t * self.lookup(lower + 1, u, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    /// Returns a texture of a single row of texels going from black to white
+    fn ramp(filter: Filter, wrap: Wrap, transform: UvTransform) -> Box<ImageTexture> {
+        let image = ImageBuffer::from_fn(4, 1, |i, _| {
+            let value = (i * 85) as u8;
+            Rgb([value, value, value])
+        });
+        ImageTexture::from_image(
+            &DynamicImage::ImageRgb8(image),
+            filter,
+            wrap,
+            transform,
+            ColorSpace::Linear,
+        )
+    }
+
+    fn lookup(texture: &ImageTexture, u: Float) -> Float {
+        texture.value(u, 0.5, Vec3::new()).x()
+    }
+
+    #[test]
+    fn wrap_modes_extend_the_image() {
+        let identity = UvTransform::identity();
+        let repeat = ramp(Filter::Nearest, Wrap::Repeat, identity);
+        let mirror = ramp(Filter::Nearest, Wrap::Mirror, identity);
+        let clamp = ramp(Filter::Nearest, Wrap::Clamp, identity);
+
+        assert_eq!(lookup(&repeat, 1.125), lookup(&repeat, 0.125));
+        assert_eq!(lookup(&repeat, -0.125), lookup(&repeat, 0.875));
+        assert_eq!(lookup(&mirror, 1.125), lookup(&mirror, 0.875));
+        assert_eq!(lookup(&mirror, -0.125), lookup(&mirror, 0.125));
+        assert_eq!(lookup(&clamp, 1.5), 1.0);
+        assert_eq!(lookup(&clamp, -0.5), 0.0);
+    }
+
+    #[test]
+    fn filters_interpolate_between_texels() {
+        let identity = UvTransform::identity();
+        let nearest = ramp(Filter::Nearest, Wrap::Clamp, identity);
+        let bilinear = ramp(Filter::Bilinear, Wrap::Clamp, identity);
+        let bicubic = ramp(Filter::Bicubic, Wrap::Clamp, identity);
+
+        // NOTE: Texels sit at u = 0.125, 0.375, 0.625 and 0.875, halfway between the middle
+        // two the ramp is at one half
+        assert!((lookup(&nearest, 0.49) - 1.0 / 3.0).abs() < 1e-6);
+        assert!((lookup(&bilinear, 0.5) - 0.5).abs() < 1e-6);
+        assert!((lookup(&bilinear, 0.3125) - 0.25).abs() < 1e-6);
+        // NOTE: Catmull-Rom splines reproduce linear ramps exactly
+        assert!((lookup(&bicubic, 0.4375) - 5.0 / 12.0).abs() < 1e-6);
+        assert!((lookup(&bicubic, 0.5) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn transforms_tile_and_shift_the_image() {
+        let tiled = ramp(
+            Filter::Nearest,
+            Wrap::Repeat,
+            UvTransform::new((2.0, 1.0), (0.25, 0.0), 0.0),
+        );
+        let plain = ramp(Filter::Nearest, Wrap::Repeat, UvTransform::identity());
+        assert_eq!(lookup(&tiled, 0.1), lookup(&plain, 0.45));
+        assert_eq!(lookup(&tiled, 0.6), lookup(&plain, 0.45));
+
+        let rotated = UvTransform::new((1.0, 1.0), (0.0, 0.0), 90.0).apply(1.0, 0.0);
+        assert!(rotated.0.abs() < 1e-6 && (rotated.1 - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    #[allow(clippy::useless_conversion)]
+    fn high_dynamic_range_images_keep_values_above_one() {
+        let directory = std::env::temp_dir().join(format!("raytracer-hdr-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let color = |i: usize| (4.0 * i as f32 + 0.5, 0.25, 16.0);
+
+        let hdr = directory.join("ramp.hdr");
+        let pixels: Vec<Rgb<f32>> = (0..4)
+            .map(|i| {
+                let (r, g, b) = color(i);
+                Rgb([r, g, b])
+            })
+            .collect();
+        image::hdr::HDREncoder::new(File::create(&hdr).unwrap())
+            .encode(&pixels, 4, 1)
+            .unwrap();
+
+        let exr = directory.join("ramp.exr");
+        exr::prelude::write_rgba_file(&exr, 4, 1, |i, _| {
+            let (r, g, b) = color(i);
+            (r, g, b, 1.0)
+        })
+        .unwrap();
+
+        let identity = UvTransform::identity();
+        for path in [hdr, exr].iter() {
+            let texture = ImageTexture::with_options(
+                path.to_str().unwrap(),
+                Filter::Nearest,
+                Wrap::Clamp,
+                identity,
+                None,
+            );
+            for i in 0..4 {
+                let (r, g, b) = color(i);
+                let value = texture.value((i as Float + 0.5) / 4.0, 0.5, Vec3::new());
+                let expected = Vec3::with_values(r.into(), g.into(), b.into());
+                // NOTE: RGBE shares one exponent between the channels, which rounds the smaller
+                // ones off
+                assert!(
+                    (value - expected).len() < 0.01 * expected.len(),
+                    "{:?}",
+                    value
+                );
+            }
         }
-        if j > self.ny - 1 {
-            j = self.ny - 1
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn wide_footprints_average_the_image() {
+        let texture = ramp(Filter::Bilinear, Wrap::Repeat, UvTransform::identity());
+        let footprint = |du: Float| Footprint {
+            dudx: du,
+            dvdx: 0.0,
+            dudy: 0.0,
+            dvdy: du,
+        };
+
+        // NOTE: Footprints within a texel leave the lookup unfiltered
+        let sharp = texture.filtered(0.3125, 0.5, Vec3::new(), &footprint(0.1));
+        assert_eq!(sharp, texture.value(0.3125, 0.5, Vec3::new()));
+
+        // NOTE: A pixel covering the whole row sees the average of the ramp wherever it looks
+        for &u in [0.1, 0.5, 0.9].iter() {
+            let blurred = texture.filtered(u, 0.5, Vec3::new(), &footprint(1.0));
+            assert!((blurred.x() - 0.5).abs() < 1e-6, "{:?}", blurred);
         }
-        let (r, g, b, _) = unsafe { self.image.unsafe_get_pixel(i, j).channels4() };
 
-        Vec3::with_values(r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0)
+        // NOTE: Halfway between levels the lookup blends them
+        let halfway = texture.filtered(0.125, 0.5, Vec3::new(), &footprint(2.0 / 4.0));
+        let first = texture.value(0.125, 0.5, Vec3::new()).x();
+        assert!(halfway.x() > first && halfway.x() < 0.5, "{:?}", halfway);
     }
 }
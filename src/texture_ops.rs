@@ -0,0 +1,332 @@
+use crate::procedural::ColorRamp;
+use crate::texture::{Footprint, Texture, UvTransform};
+use crate::vec3::{Float, Vec3};
+
+// NOTE: Operators look their inputs up through a closure, so that `value` and `filtered` share
+// one implementation and filtered lookups reach the image textures at the leaves of a graph.
+
+/// Function returns the average of the channels of a color, how colored inputs are read where
+/// an operator needs a single value
+fn average(color: Vec3) -> Float {
+    (color.x() + color.y() + color.z()) / 3.0
+}
+
+/// MixTexture blends from the first texture to the second as the factor goes from zero to one
+#[derive(Clone, Debug)]
+pub struct MixTexture {
+    first: Box<dyn Texture>,
+    second: Box<dyn Texture>,
+    factor: Box<dyn Texture>,
+}
+
+impl MixTexture {
+    pub fn new(
+        first: Box<dyn Texture>,
+        second: Box<dyn Texture>,
+        factor: Box<dyn Texture>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            first,
+            second,
+            factor,
+        })
+    }
+
+    fn combine<F: Fn(&dyn Texture) -> Vec3>(&self, lookup: F) -> Vec3 {
+        let t = average(lookup(self.factor.as_ref())).clamp(0.0, 1.0);
+        (1.0 - t) * lookup(self.first.as_ref()) + t * lookup(self.second.as_ref())
+    }
+}
+
+impl Texture for MixTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.combine(|texture| texture.value(u, v, p))
+    }
+
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        self.combine(|texture| texture.filtered(u, v, p, footprint))
+    }
+}
+
+/// Enum lists the ways two textures can be combined channel by channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arithmetic {
+    Multiply,
+    Add,
+}
+
+/// ArithmeticTexture multiplies or adds two textures channel by channel
+#[derive(Clone, Debug)]
+pub struct ArithmeticTexture {
+    operation: Arithmetic,
+    first: Box<dyn Texture>,
+    second: Box<dyn Texture>,
+}
+
+impl ArithmeticTexture {
+    pub fn new(
+        operation: Arithmetic,
+        first: Box<dyn Texture>,
+        second: Box<dyn Texture>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            operation,
+            first,
+            second,
+        })
+    }
+
+    fn combine<F: Fn(&dyn Texture) -> Vec3>(&self, lookup: F) -> Vec3 {
+        let (first, second) = (lookup(self.first.as_ref()), lookup(self.second.as_ref()));
+        match self.operation {
+            Arithmetic::Multiply => first * second,
+            Arithmetic::Add => first + second,
+        }
+    }
+}
+
+impl Texture for ArithmeticTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.combine(|texture| texture.value(u, v, p))
+    }
+
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        self.combine(|texture| texture.filtered(u, v, p, footprint))
+    }
+}
+
+/// Enum lists the adjustments applied to the value of a single texture
+#[derive(Clone, Debug)]
+pub enum Adjustment {
+    /// Looks the average of the channels up in a color ramp
+    Ramp(ColorRamp),
+    /// Maps every channel linearly from the first range to the second, the bounds of the first
+    /// range have to differ
+    Remap((Float, Float), (Float, Float)),
+    /// Subtracts every channel from one
+    Invert,
+    /// Limits every channel to a range
+    Clamp(Float, Float),
+}
+
+impl Adjustment {
+    fn apply(&self, color: Vec3) -> Vec3 {
+        let channels = |f: &dyn Fn(Float) -> Float| {
+            Vec3::with_values(f(color.x()), f(color.y()), f(color.z()))
+        };
+        match self {
+            Adjustment::Ramp(ramp) => ramp.at(average(color)),
+            Adjustment::Remap(from, to) => {
+                channels(&|value| to.0 + (value - from.0) / (from.1 - from.0) * (to.1 - to.0))
+            }
+            Adjustment::Invert => channels(&|value| 1.0 - value),
+            Adjustment::Clamp(min, max) => channels(&|value| value.clamp(*min, *max)),
+        }
+    }
+}
+
+/// AdjustedTexture applies an adjustment to the value of another texture
+#[derive(Clone, Debug)]
+pub struct AdjustedTexture {
+    texture: Box<dyn Texture>,
+    adjustment: Adjustment,
+}
+
+impl AdjustedTexture {
+    pub fn new(texture: Box<dyn Texture>, adjustment: Adjustment) -> Box<Self> {
+        Box::new(Self {
+            texture,
+            adjustment,
+        })
+    }
+}
+
+impl Texture for AdjustedTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.adjustment.apply(self.texture.value(u, v, p))
+    }
+
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        self.adjustment
+            .apply(self.texture.filtered(u, v, p, footprint))
+    }
+}
+
+/// Enum selects the channel of a color a channel texture reads
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    R,
+    G,
+    B,
+    /// Brightness of a linear sRGB color as the eye perceives it
+    Luminance,
+}
+
+/// ChannelTexture spreads one channel of another texture over all three, such as the roughness
+/// packed into the green channel of an image
+#[derive(Clone, Debug)]
+pub struct ChannelTexture {
+    texture: Box<dyn Texture>,
+    channel: Channel,
+}
+
+impl ChannelTexture {
+    pub fn new(texture: Box<dyn Texture>, channel: Channel) -> Box<Self> {
+        Box::new(Self { texture, channel })
+    }
+
+    fn select(&self, color: Vec3) -> Vec3 {
+        let value = match self.channel {
+            Channel::R => color.x(),
+            Channel::G => color.y(),
+            Channel::B => color.z(),
+            Channel::Luminance => color.dot(Vec3::with_values(0.2126, 0.7152, 0.0722)),
+        };
+        Vec3::with_values(value, value, value)
+    }
+}
+
+impl Texture for ChannelTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        self.select(self.texture.value(u, v, p))
+    }
+
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        self.select(self.texture.filtered(u, v, p, footprint))
+    }
+}
+
+/// TransformedTexture moves the coordinates another texture is looked up at. Texture
+/// coordinates go through a UV transform and points are scaled before they are offset, which
+/// scales procedural textures working in space.
+#[derive(Clone, Debug)]
+pub struct TransformedTexture {
+    texture: Box<dyn Texture>,
+    uv: UvTransform,
+    scale: Vec3,
+    offset: Vec3,
+}
+
+impl TransformedTexture {
+    pub fn new(texture: Box<dyn Texture>, uv: UvTransform, scale: Vec3, offset: Vec3) -> Box<Self> {
+        Box::new(Self {
+            texture,
+            uv,
+            scale,
+            offset,
+        })
+    }
+}
+
+impl Texture for TransformedTexture {
+    fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+        let (u, v) = self.uv.apply(u, v);
+        self.texture.value(u, v, self.scale * p + self.offset)
+    }
+
+    fn filtered(&self, u: Float, v: Float, p: Vec3, footprint: &Footprint) -> Vec3 {
+        let (u, v) = self.uv.apply(u, v);
+        let footprint = self.uv.apply_footprint(footprint);
+        self.texture
+            .filtered(u, v, self.scale * p + self.offset, &footprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::SolidTexture;
+
+    fn color(r: Float, g: Float, b: Float) -> Box<dyn Texture> {
+        SolidTexture::new(Vec3::with_values(r, g, b))
+    }
+
+    fn lookup(texture: &dyn Texture) -> Vec3 {
+        texture.value(0.25, 0.75, Vec3::with_values(1.0, 2.0, 3.0))
+    }
+
+    /// Texture showing its lookup coordinates, u and v in red and green and x in blue
+    #[derive(Clone, Debug)]
+    struct Coordinates;
+
+    impl Texture for Coordinates {
+        fn value(&self, u: Float, v: Float, p: Vec3) -> Vec3 {
+            Vec3::with_values(u, v, p.x())
+        }
+
+        fn filtered(&self, _: Float, _: Float, _: Vec3, footprint: &Footprint) -> Vec3 {
+            Vec3::with_values(footprint.dudx, footprint.dvdx, 0.0)
+        }
+    }
+
+    #[test]
+    fn operators_combine_their_inputs() {
+        let mix = MixTexture::new(
+            color(1.0, 0.0, 0.0),
+            color(0.0, 0.0, 1.0),
+            color(0.25, 0.25, 0.25),
+        );
+        assert_eq!(lookup(mix.as_ref()), Vec3::with_values(0.75, 0.0, 0.25));
+
+        let product = ArithmeticTexture::new(
+            Arithmetic::Multiply,
+            color(0.5, 1.0, 0.0),
+            color(0.5, 0.5, 0.5),
+        );
+        assert_eq!(lookup(product.as_ref()), Vec3::with_values(0.25, 0.5, 0.0));
+        let sum =
+            ArithmeticTexture::new(Arithmetic::Add, color(0.5, 1.0, 0.0), color(0.5, 0.5, 0.5));
+        assert_eq!(lookup(sum.as_ref()), Vec3::with_values(1.0, 1.5, 0.5));
+    }
+
+    #[test]
+    fn adjustments_change_single_textures() {
+        let adjust =
+            |adjustment| lookup(AdjustedTexture::new(color(0.2, 0.5, 1.5), adjustment).as_ref());
+        assert_eq!(
+            adjust(Adjustment::Invert),
+            Vec3::with_values(0.8, 0.5, -0.5)
+        );
+        assert_eq!(
+            adjust(Adjustment::Clamp(0.0, 1.0)),
+            Vec3::with_values(0.2, 0.5, 1.0)
+        );
+        let remapped = adjust(Adjustment::Remap((0.0, 2.0), (1.0, 0.0)));
+        assert!((remapped - Vec3::with_values(0.9, 0.75, 0.25)).len() < 1e-6);
+        let ramped = adjust(Adjustment::Ramp(ColorRamp::new(vec![
+            (0.0, Vec3::new()),
+            (1.4, Vec3::with_values(0.0, 1.4, 0.0)),
+        ])));
+        assert!((ramped - Vec3::with_values(0.0, 2.2 / 3.0, 0.0)).len() < 1e-6);
+
+        let green = ChannelTexture::new(color(0.2, 0.5, 1.5), Channel::G);
+        assert_eq!(lookup(green.as_ref()), Vec3::with_values(0.5, 0.5, 0.5));
+        let luminance = ChannelTexture::new(color(1.0, 1.0, 1.0), Channel::Luminance);
+        assert!((lookup(luminance.as_ref()).x() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn transforms_move_lookups_and_their_footprints() {
+        let transformed = TransformedTexture::new(
+            Box::new(Coordinates),
+            UvTransform::new((2.0, 4.0), (0.5, 0.0), 0.0),
+            Vec3::with_values(3.0, 3.0, 3.0),
+            Vec3::with_values(-1.0, 0.0, 0.0),
+        );
+        assert_eq!(
+            lookup(transformed.as_ref()),
+            Vec3::with_values(1.0, 3.0, 2.0)
+        );
+
+        // NOTE: Filtered lookups reach the input through nested operators
+        let nested = AdjustedTexture::new(transformed, Adjustment::Clamp(0.0, 10.0));
+        let footprint = Footprint {
+            dudx: 0.1,
+            dvdx: 0.2,
+            dudy: 0.0,
+            dvdy: 0.0,
+        };
+        let filtered = nested.filtered(0.0, 0.0, Vec3::new(), &footprint);
+        assert!((filtered - Vec3::with_values(0.2, 0.8, 0.0)).len() < 1e-6);
+    }
+}
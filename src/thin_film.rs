@@ -0,0 +1,209 @@
+use crate::spectrum::{wavelength_weight, LAMBDA_MAX, LAMBDA_MIN};
+use crate::vec3::{Float, Vec3, PI};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Number of wavelengths the reflectance of a film is integrated over for paths that were not
+/// split up into single wavelengths
+const FILM_WAVELENGTHS: usize = 16;
+
+/// Complex number, used for the amplitudes and phases of light waves in a film
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Complex {
+    pub re: Float,
+    pub im: Float,
+}
+
+impl Complex {
+    pub fn new(re: Float, im: Float) -> Self {
+        Self { re, im }
+    }
+
+    pub fn real(re: Float) -> Self {
+        Self::new(re, 0.0)
+    }
+
+    pub fn norm_sqr(&self) -> Float {
+        self.re * self.re + self.im * self.im
+    }
+
+    /// Returns the principal square root, whose imaginary part is never negative for negative
+    /// real numbers, so that evanescent waves decay
+    pub fn sqrt(&self) -> Self {
+        let r = self.norm_sqr().sqrt();
+        let re = (0.5 * (r + self.re)).max(0.0).sqrt();
+        let im = (0.5 * (r - self.re)).max(0.0).sqrt();
+        Self::new(re, if self.im < 0.0 { -im } else { im })
+    }
+
+    /// Returns `e^(i z)`
+    pub fn exp_i(&self) -> Self {
+        let magnitude = (-self.im).exp();
+        Self::new(magnitude * self.re.cos(), magnitude * self.re.sin())
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, rhs: Complex) -> Complex {
+        Complex::new(self.re + rhs.re, self.im + rhs.im)
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, rhs: Complex) -> Complex {
+        Complex::new(self.re - rhs.re, self.im - rhs.im)
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, rhs: Complex) -> Complex {
+        Complex::new(
+            self.re * rhs.re - self.im * rhs.im,
+            self.re * rhs.im + self.im * rhs.re,
+        )
+    }
+}
+
+impl Div for Complex {
+    type Output = Complex;
+
+    fn div(self, rhs: Complex) -> Complex {
+        let denominator = rhs.norm_sqr();
+        Complex::new(
+            (self.re * rhs.re + self.im * rhs.im) / denominator,
+            (self.im * rhs.re - self.re * rhs.im) / denominator,
+        )
+    }
+}
+
+impl Neg for Complex {
+    type Output = Complex;
+
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+/// Returns the cosine of the angle to the normal of light refracted into a layer of index `n`,
+/// `n_sin` being the invariant of Snell's law
+fn refracted_cos(n: Complex, n_sin: Float) -> Complex {
+    let sin = Complex::real(n_sin) / n;
+    (Complex::real(1.0) - sin * sin).sqrt()
+}
+
+/// Returns the amplitude reflection coefficients of an interface for s and p polarized light
+fn amplitudes(n_i: Complex, cos_i: Complex, n_t: Complex, cos_t: Complex) -> (Complex, Complex) {
+    let s = (n_i * cos_i - n_t * cos_t) / (n_i * cos_i + n_t * cos_t);
+    let p = (n_t * cos_i - n_i * cos_t) / (n_t * cos_i + n_i * cos_t);
+    (s, p)
+}
+
+/// Struct describes a thin transparent film on top of a surface, such as the wall of a soap
+/// bubble, oil on water or the oxide layer of anodized metal. Light reflected off the top and the
+/// bottom of the film interferes, which colors the reflection depending on the viewing angle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThinFilm {
+    /// Thickness of the film in nanometers
+    pub thickness: Float,
+    /// Index of refraction of the film
+    pub ior: Float,
+}
+
+impl ThinFilm {
+    pub fn new(thickness: Float, ior: Float) -> Self {
+        Self { thickness, ior }
+    }
+
+    /// Returns the reflectance at `lambda` nanometers of the film between a medium of index `n_i`
+    /// light arrives from at `cos_i` to the normal and a substrate of index `n_t`, averaged over
+    /// both polarizations. Uses the Airy summation of all waves bouncing inside the film.
+    pub fn reflectance_at(&self, cos_i: Float, n_i: Float, n_t: Complex, lambda: Float) -> Float {
+        let cos_i = cos_i.clamp(0.0, 1.0);
+        let n_sin = n_i * (1.0 - cos_i * cos_i).sqrt();
+        let n_i = Complex::real(n_i);
+        let cos_i = Complex::real(cos_i);
+        let n_f = Complex::real(self.ior);
+        let cos_f = refracted_cos(n_f, n_sin);
+        let cos_t = refracted_cos(n_t, n_sin);
+
+        // NOTE: Phase difference picked up by a wave going down and back up through the film
+        let delta = Complex::real(4.0 * PI * self.thickness / lambda) * n_f * cos_f;
+        let phase = delta.exp_i();
+
+        let (top_s, top_p) = amplitudes(n_i, cos_i, n_f, cos_f);
+        let (bottom_s, bottom_p) = amplitudes(n_f, cos_f, n_t, cos_t);
+        let one = Complex::real(1.0);
+        let s = (top_s + bottom_s * phase) / (one + top_s * bottom_s * phase);
+        let p = (top_p + bottom_p * phase) / (one + top_p * bottom_p * phase);
+        (0.5 * (s.norm_sqr() + p.norm_sqr())).min(1.0)
+    }
+
+    /// Returns the color of the reflectance of the film, `n_t` giving the index of the substrate
+    /// at a wavelength. Paths traced at a single `wavelength` see the reflectance at that
+    /// wavelength alone, others get it integrated over the visible range.
+    pub fn reflectance<F: Fn(Float) -> Complex>(
+        &self,
+        cos_i: Float,
+        n_i: Float,
+        n_t: F,
+        wavelength: Option<Float>,
+    ) -> Vec3 {
+        if let Some(lambda) = wavelength {
+            let reflectance = self.reflectance_at(cos_i, n_i, n_t(lambda), lambda);
+            return Vec3::with_values(reflectance, reflectance, reflectance);
+        }
+
+        // NOTE: The weights are normalized by their sum so that a constant reflectance keeps its
+        // value exactly, despite the coarse integration
+        let mut total = Vec3::new();
+        let mut weights = Vec3::new();
+        for i in 0..FILM_WAVELENGTHS {
+            let lambda = LAMBDA_MIN
+                + (i as Float + 0.5) * (LAMBDA_MAX - LAMBDA_MIN) / FILM_WAVELENGTHS as Float;
+            let weight = wavelength_weight(lambda);
+            total += weight * self.reflectance_at(cos_i, n_i, n_t(lambda), lambda);
+            weights += weight;
+        }
+
+        let mut reflectance = Vec3::new();
+        for i in 0..3 {
+            reflectance[i] = (total[i] / weights[i]).clamp(0.0, 1.0);
+        }
+        reflectance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{fresnel_conductor, fresnel_dielectric};
+
+    #[test]
+    fn films_without_thickness_leave_the_fresnel_reflectance() {
+        let film = ThinFilm::new(0.0, 1.33);
+        for &cos in [1.0, 0.7, 0.3, 0.05].iter() {
+            let dielectric = film.reflectance_at(cos, 1.0, Complex::real(1.5), 550.0);
+            assert!((dielectric - fresnel_dielectric(cos, 1.5)).abs() < 1e-4);
+
+            let conductor = film.reflectance_at(cos, 1.0, Complex::new(0.2, 3.9), 550.0);
+            assert!((conductor - fresnel_conductor(cos, 0.2, 3.9)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn quarter_wave_films_cancel_reflections() {
+        let ior = (1.5 as Float).sqrt();
+        let film = ThinFilm::new(550.0 / (4.0 * ior), ior);
+        assert!(film.reflectance_at(1.0, 1.0, Complex::real(1.5), 550.0) < 1e-6);
+
+        // NOTE: Other wavelengths are no longer cancelled out completely, tinting the reflection
+        let reflectance = film.reflectance(1.0, 1.0, |_| Complex::real(1.5), None);
+        assert!(reflectance.g() < reflectance.r() && reflectance.g() < reflectance.b());
+        assert!(reflectance.r() < fresnel_dielectric(1.0, 1.5));
+    }
+}
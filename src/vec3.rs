@@ -2,43 +2,90 @@ use std::ops::{
     Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, MulAssign, Neg, Sub, SubAssign,
 };
 
-#[derive(Clone, Copy, Debug)]
-pub struct Vec3([f64; 3]);
+/// Scalar type used for all geometry and shading math. Double precision by default, single
+/// precision when the crate is built with the `f32` feature.
+#[cfg(not(feature = "f32"))]
+pub type Float = f64;
+/// Scalar type used for all geometry and shading math. Double precision by default, single
+/// precision when the crate is built with the `f32` feature.
+#[cfg(feature = "f32")]
+pub type Float = f32;
+
+pub const PI: Float = std::f64::consts::PI as Float;
+
+/// Conservative bound on the relative rounding error accumulated by `n` successive floating point
+/// operations, see PBR 3.9.1.
+pub fn gamma(n: i32) -> Float {
+    let e = Float::EPSILON * 0.5;
+    (n as Float * e) / (1.0 - n as Float * e)
+}
+
+/// Returns the smallest float that is greater than `v`
+pub fn next_float_up(v: Float) -> Float {
+    if v.is_infinite() && v > 0.0 {
+        return v;
+    }
+
+    // NOTE: -0.0 and 0.0 have different bit patterns, step up from positive zero
+    let v = if v == 0.0 { 0.0 } else { v };
+    if v >= 0.0 {
+        Float::from_bits(v.to_bits() + 1)
+    } else {
+        Float::from_bits(v.to_bits() - 1)
+    }
+}
+
+/// Returns the largest float that is smaller than `v`
+pub fn next_float_down(v: Float) -> Float {
+    if v.is_infinite() && v < 0.0 {
+        return v;
+    }
+
+    let v = if v == 0.0 { -0.0 } else { v };
+    if v > 0.0 {
+        Float::from_bits(v.to_bits() - 1)
+    } else {
+        Float::from_bits(v.to_bits() + 1)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Vec3([Float; 3]);
 
 impl Vec3 {
     pub fn new() -> Self {
         Self([0.0, 0.0, 0.0])
     }
 
-    pub fn with_values(x: f64, y: f64, z: f64) -> Self {
+    pub fn with_values(x: Float, y: Float, z: Float) -> Self {
         Self([x, y, z])
     }
 
-    pub fn x(&self) -> f64 {
+    pub fn x(&self) -> Float {
         self[0]
     }
 
-    pub fn y(&self) -> f64 {
+    pub fn y(&self) -> Float {
         self[1]
     }
 
-    pub fn z(&self) -> f64 {
+    pub fn z(&self) -> Float {
         self[2]
     }
 
-    pub fn r(&self) -> f64 {
+    pub fn r(&self) -> Float {
         self[0]
     }
 
-    pub fn g(&self) -> f64 {
+    pub fn g(&self) -> Float {
         self[1]
     }
 
-    pub fn b(&self) -> f64 {
+    pub fn b(&self) -> Float {
         self[2]
     }
 
-    pub fn dot(&self, rhs: Vec3) -> f64 {
+    pub fn dot(&self, rhs: Vec3) -> Float {
         self[0] * rhs.x() + self[1] * rhs.y() + self[2] * rhs.z()
     }
 
@@ -50,11 +97,11 @@ impl Vec3 {
         Self::with_values(x, y, z)
     }
 
-    pub fn len(&self) -> f64 {
+    pub fn len(&self) -> Float {
         self.squared_len().sqrt()
     }
 
-    pub fn squared_len(&self) -> f64 {
+    pub fn squared_len(&self) -> Float {
         self[0] * self[0] + self[1] * self[1] + self[2] * self[2]
     }
 
@@ -69,6 +116,10 @@ impl Vec3 {
         self[2] *= k;
     }
 
+    pub fn abs(&self) -> Self {
+        Self::with_values(self[0].abs(), self[1].abs(), self[2].abs())
+    }
+
     pub fn update(&mut self, rhs: Vec3) {
         self[0] = rhs.x();
         self[1] = rhs.y();
@@ -76,6 +127,70 @@ impl Vec3 {
     }
 }
 
+/// Struct describes an orthonormal basis, used to move directions between world space and a local
+/// shading frame in which `w` is the z axis
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    /// Builds a basis around the unit vector `n`, using the branchless construction of Duff et al.
+    /// "Building an Orthonormal Basis, Revisited" which stays stable for any normal.
+    pub fn from_w(n: Vec3) -> Self {
+        let sign = (1.0 as Float).copysign(n.z());
+        let a = -1.0 / (sign + n.z());
+        let b = n.x() * n.y() * a;
+
+        Self {
+            u: Vec3::with_values(1.0 + sign * n.x() * n.x() * a, sign * b, -sign * n.x()),
+            v: Vec3::with_values(b, sign + n.y() * n.y() * a, -n.y()),
+            w: n,
+        }
+    }
+
+    /// Builds a basis around the unit vector `n` whose `u` axis follows `tangent` projected into
+    /// the plane normal to `n`, so that anisotropic lobes line up with the texture coordinates.
+    /// Falls back to `from_w` where the tangent vanishes or is parallel to `n`.
+    pub fn from_w_tangent(n: Vec3, tangent: Vec3) -> Self {
+        let u = tangent - n.dot(tangent) * n;
+        if u.squared_len() <= 1e-12 * tangent.squared_len() {
+            return Self::from_w(n);
+        }
+
+        let u = u.unit_vector();
+        Self {
+            u,
+            v: n.cross(u),
+            w: n,
+        }
+    }
+
+    pub fn u(&self) -> Vec3 {
+        self.u
+    }
+
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    pub fn w(&self) -> Vec3 {
+        self.w
+    }
+
+    /// Transforms a direction from the local frame to world space
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        a.x() * self.u + a.y() * self.v + a.z() * self.w
+    }
+
+    /// Transforms a direction from world space to the local frame
+    pub fn to_local(&self, a: Vec3) -> Vec3 {
+        Vec3::with_values(a.dot(self.u), a.dot(self.v), a.dot(self.w))
+    }
+}
+
 impl Default for Vec3 {
     fn default() -> Self {
         Self::new()
@@ -90,10 +205,10 @@ impl Add for Vec3 {
     }
 }
 
-impl Add<f64> for Vec3 {
+impl Add<Float> for Vec3 {
     type Output = Vec3;
 
-    fn add(self, rhs: f64) -> Vec3 {
+    fn add(self, rhs: Float) -> Vec3 {
         Vec3::with_values(self[0] + rhs, self[1] + rhs, self[2] + rhs)
     }
 }
@@ -146,35 +261,35 @@ impl DivAssign<Vec3> for Vec3 {
     }
 }
 
-impl Mul<f64> for Vec3 {
+impl Mul<Float> for Vec3 {
     type Output = Vec3;
 
-    fn mul(self, rhs: f64) -> Vec3 {
+    fn mul(self, rhs: Float) -> Vec3 {
         Vec3::with_values(self[0] * rhs, self[1] * rhs, self[2] * rhs)
     }
 }
 
-impl MulAssign<f64> for Vec3 {
-    fn mul_assign(&mut self, rhs: f64) {
+impl MulAssign<Float> for Vec3 {
+    fn mul_assign(&mut self, rhs: Float) {
         *self = Vec3::with_values(self[0] * rhs, self[1] * rhs, self[2] * rhs);
     }
 }
 
-impl Div<f64> for Vec3 {
+impl Div<Float> for Vec3 {
     type Output = Vec3;
 
-    fn div(self, rhs: f64) -> Vec3 {
+    fn div(self, rhs: Float) -> Vec3 {
         Vec3::with_values(self[0] / rhs, self[1] / rhs, self[2] / rhs)
     }
 }
 
-impl DivAssign<f64> for Vec3 {
-    fn div_assign(&mut self, rhs: f64) {
+impl DivAssign<Float> for Vec3 {
+    fn div_assign(&mut self, rhs: Float) {
         *self = Vec3::with_values(self[0] / rhs, self[1] / rhs, self[2] / rhs);
     }
 }
 
-impl Mul<Vec3> for f64 {
+impl Mul<Vec3> for Float {
     type Output = Vec3;
 
     fn mul(self, lhs: Vec3) -> Vec3 {
@@ -190,22 +305,22 @@ impl Neg for Vec3 {
     }
 }
 
-impl From<(f64, f64, f64)> for Vec3 {
-    fn from(params: (f64, f64, f64)) -> Vec3 {
+impl From<(Float, Float, Float)> for Vec3 {
+    fn from(params: (Float, Float, Float)) -> Vec3 {
         Vec3::with_values(params.0, params.1, params.2)
     }
 }
 
 impl Index<usize> for Vec3 {
-    type Output = f64;
+    type Output = Float;
 
-    fn index(&self, index: usize) -> &f64 {
+    fn index(&self, index: usize) -> &Float {
         &self.0[index]
     }
 }
 
 impl IndexMut<usize> for Vec3 {
-    fn index_mut(&mut self, index: usize) -> &mut f64 {
+    fn index_mut(&mut self, index: usize) -> &mut Float {
         &mut self.0[index]
     }
 }